@@ -0,0 +1,253 @@
+//! Registered players, stored in a JSON file with argon2-hashed passwords.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use argon2::{
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{SaltString, rand_core::OsRng},
+};
+use axum::{
+    Json,
+    extract::{State, rejection::JsonRejection},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::game::{AppState, Player, ServerError};
+
+/// Account is a registered player.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub user_id: String,
+    pub username: String,
+    pub display_name: String,
+    /// password_hash is the argon2 hash of the password, in the PHC string format.
+    pub password_hash: String,
+}
+
+impl Account {
+    pub fn player(&self) -> Player {
+        return Player {
+            user_id: self.user_id.clone(),
+            display_name: self.display_name.clone(),
+        };
+    }
+}
+
+/// AccountStore keeps the accounts in memory, keyed by username,
+/// and writes all of them to its file whenever one is added.
+pub struct AccountStore {
+    path: PathBuf,
+    accounts: Mutex<HashMap<String, Account>>,
+    /// dummy_password_hash is verified against when the username is unknown,
+    /// so an unknown username takes as long to reject as a wrong password.
+    dummy_password_hash: String,
+}
+
+impl AccountStore {
+    /// load reads the accounts from `path`. A missing file is an empty store.
+    pub fn load(path: PathBuf) -> AccountStore {
+        let accounts: Vec<Account> = match fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+                panic!("{} is not a valid accounts file: {e}", path.display());
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => panic!("Fail to read {}: {e}", path.display()),
+        };
+        tracing::debug!("Loaded {} accounts from {}", accounts.len(), path.display());
+
+        let dummy_password_hash = hash_password(&uuid::Uuid::now_v7().to_string())
+            .unwrap_or_else(|e| panic!("Fail to hash the dummy password: {e}"));
+        return AccountStore {
+            path,
+            dummy_password_hash,
+            accounts: Mutex::new(
+                accounts
+                    .into_iter()
+                    .map(|account| (account.username.clone(), account))
+                    .collect(),
+            ),
+        };
+    }
+
+    pub fn register(
+        &self,
+        username: String,
+        display_name: String,
+        password: &str,
+    ) -> Result<Account, ServerError> {
+        let password_hash = match hash_password(password) {
+            Ok(hash) => hash,
+            Err(e) => {
+                tracing::error!("Fail to hash password: {e}");
+                return Err(ServerError::InternalError);
+            }
+        };
+
+        let mut accounts = match self.accounts.lock() {
+            Ok(accounts) => accounts,
+            Err(e) => {
+                tracing::error!("Fail to lock accounts: {e}");
+                return Err(ServerError::InternalError);
+            }
+        };
+        if accounts.contains_key(&username) {
+            return Err(ServerError::UsernameTaken);
+        }
+
+        let account = Account {
+            user_id: uuid::Uuid::now_v7().to_string(),
+            username: username.clone(),
+            display_name,
+            password_hash,
+        };
+        accounts.insert(username.clone(), account.clone());
+        if let Err(e) = self.save(&accounts) {
+            tracing::error!("Fail to save accounts to {}: {e}", self.path.display());
+            accounts.remove(&username);
+            return Err(ServerError::InternalError);
+        }
+        return Ok(account);
+    }
+
+    /// verify returns the account when the password matches.
+    /// An unknown username and a wrong password are the same error, so usernames cannot be probed.
+    pub fn verify(&self, username: &str, password: &str) -> Result<Account, ServerError> {
+        let account = match self.accounts.lock() {
+            Ok(accounts) => accounts.get(username).cloned(),
+            Err(e) => {
+                tracing::error!("Fail to lock accounts: {e}");
+                return Err(ServerError::InternalError);
+            }
+        };
+        let Some(account) = account else {
+            let _ = verify_password(&self.dummy_password_hash, password);
+            return Err(ServerError::InvalidCredentials);
+        };
+
+        return match verify_password(&account.password_hash, password) {
+            Ok(true) => Ok(account),
+            Ok(false) => Err(ServerError::InvalidCredentials),
+            Err(e) => {
+                tracing::error!("Password hash of {username} is invalid: {e}");
+                Err(ServerError::InternalError)
+            }
+        };
+    }
+
+    /// save writes the accounts to a temporary file first, so a crash cannot truncate the store.
+    fn save(&self, accounts: &HashMap<String, Account>) -> io::Result<()> {
+        let mut accounts: Vec<&Account> = accounts.values().collect();
+        accounts.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+        let content = serde_json::to_vec_pretty(&accounts)?;
+
+        let temporary_path = self.path.with_extension("json.tmp");
+        fs::write(&temporary_path, content)?;
+        return fs::rename(&temporary_path, &self.path);
+    }
+}
+
+/// hash_password returns the argon2 hash of the password, with a random salt, in the PHC string format.
+fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    return Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string());
+}
+
+/// verify_password tells whether the password matches the hash. It fails when the hash is not valid.
+fn verify_password(
+    password_hash: &str,
+    password: &str,
+) -> Result<bool, argon2::password_hash::Error> {
+    let password_hash = PasswordHash::new(password_hash)?;
+    return Ok(Argon2::default()
+        .verify_password(password.as_bytes(), &password_hash)
+        .is_ok());
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RegisterBody {
+    pub username: String,
+    pub password: String,
+    /// display_name is shown to other players. It defaults to the username.
+    pub display_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LoginBody {
+    pub username: String,
+    pub password: String,
+}
+
+/// register creates an account and issues a session for it.
+pub async fn register(
+    State(state): State<AppState>,
+    body: Result<Json<RegisterBody>, JsonRejection>,
+) -> Response {
+    let body = match body {
+        Ok(Json(body)) => body,
+        Err(e) => return crate::rest::invalid_body(e),
+    };
+    let username = body.username.trim().to_string();
+    let display_name = body
+        .display_name
+        .map(|display_name| display_name.trim().to_string())
+        .unwrap_or_else(|| username.clone());
+    if username.is_empty() || display_name.is_empty() || body.password.is_empty() {
+        return crate::rest::error_response(None, None, ServerError::InvalidMessage);
+    }
+
+    // hashing is slow on purpose, so it must not block the runtime
+    let accounts = Arc::clone(&state.accounts);
+    let account_result = tokio::task::spawn_blocking(move || {
+        return accounts.register(username, display_name, &body.password);
+    })
+    .await;
+    return match account_result {
+        Ok(Ok(account)) => session_response(&state, StatusCode::CREATED, &account),
+        Ok(Err(e)) => crate::rest::error_response(None, None, e),
+        Err(e) => {
+            tracing::error!("Registration task failed: {e}");
+            crate::rest::error_response(None, None, ServerError::InternalError)
+        }
+    };
+}
+
+/// login issues a session for an existing account.
+pub async fn login(
+    State(state): State<AppState>,
+    body: Result<Json<LoginBody>, JsonRejection>,
+) -> Response {
+    let body = match body {
+        Ok(Json(body)) => body,
+        Err(e) => return crate::rest::invalid_body(e),
+    };
+
+    let accounts = Arc::clone(&state.accounts);
+    let account_result = tokio::task::spawn_blocking(move || {
+        return accounts.verify(body.username.trim(), &body.password);
+    })
+    .await;
+    return match account_result {
+        Ok(Ok(account)) => session_response(&state, StatusCode::OK, &account),
+        Ok(Err(e)) => crate::rest::error_response(None, None, e),
+        Err(e) => {
+            tracing::error!("Login task failed: {e}");
+            crate::rest::error_response(None, None, ServerError::InternalError)
+        }
+    };
+}
+
+fn session_response(state: &AppState, status: StatusCode, account: &Account) -> Response {
+    let session = state
+        .session_keys
+        .issue(account.player(), state.config.session_ttl.as_secs());
+    return (status, Json(session)).into_response();
+}
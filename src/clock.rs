@@ -0,0 +1,253 @@
+//! Chess-style clocks, so a player cannot stall a game forever.
+
+use std::time::{Duration, Instant};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::game::GameCharacter;
+
+/// MAX_CLOCK_SECS is the longest time a clock setting can give, a day.
+const MAX_CLOCK_SECS: u64 = 24 * 60 * 60;
+
+/// ClockSettings is the time control of a room.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ClockSettings {
+    /// Fischer gives each player `base_secs` for the whole game,
+    /// and `increment_secs` more after each of their moves.
+    Fischer { base_secs: u64, increment_secs: u64 },
+    /// PerMove gives the player to move `move_secs` for each move. Unused time is lost.
+    PerMove { move_secs: u64 },
+}
+
+impl ClockSettings {
+    /// is_valid tells whether the players get any time at all, and no more than `MAX_CLOCK_SECS` at once.
+    pub fn is_valid(&self) -> bool {
+        return match self {
+            ClockSettings::Fischer {
+                base_secs,
+                increment_secs,
+            } => (1..=MAX_CLOCK_SECS).contains(base_secs) && *increment_secs <= MAX_CLOCK_SECS,
+            ClockSettings::PerMove { move_secs } => (1..=MAX_CLOCK_SECS).contains(move_secs),
+        };
+    }
+
+    fn initial_time(&self) -> Duration {
+        return match self {
+            ClockSettings::Fischer { base_secs, .. } => Duration::from_secs(*base_secs),
+            ClockSettings::PerMove { move_secs } => Duration::from_secs(*move_secs),
+        };
+    }
+}
+
+/// ClockTimes is the time each player has left, in milliseconds.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ClockTimes {
+    pub x_remaining_ms: u64,
+    pub o_remaining_ms: u64,
+}
+
+/// Clock tracks the time of both players of a running game. Only the clock of the player to move runs.
+pub struct Clock {
+    settings: ClockSettings,
+    x_remaining: Duration,
+    o_remaining: Duration,
+    /// running is the player whose time is running, and since when it was last charged.
+    running: (GameCharacter, Instant),
+}
+
+impl Clock {
+    /// start returns a clock running for `X`, who makes the first move.
+    pub fn start(settings: ClockSettings, now: Instant) -> Clock {
+        return Clock {
+            settings,
+            x_remaining: settings.initial_time(),
+            o_remaining: settings.initial_time(),
+            running: (GameCharacter::X, now),
+        };
+    }
+
+    /// press is called after a move: it stops the clock of the player who moved,
+    /// gives them their increment and starts the clock of the opponent.
+    pub fn press(&mut self, now: Instant) {
+        let (character, _) = self.running;
+        self.charge(now);
+        if let ClockSettings::Fischer { increment_secs, .. } = self.settings {
+            let remaining = self.remaining_mut(character);
+            *remaining = remaining.saturating_add(Duration::from_secs(increment_secs));
+        }
+        self.run(character.opponent(), now);
+    }
+
+    /// switch_to starts the clock of `character`, e.g. when their move is taken back.
+    /// The time spent by the running player is charged, without increment.
+    pub fn switch_to(&mut self, character: GameCharacter, now: Instant) {
+        self.charge(now);
+        self.run(character, now);
+    }
+
+    /// deadline is when the player to move runs out of time,
+    /// or `None` if that is too far in the future to be represented.
+    pub fn deadline(&self) -> Option<Instant> {
+        let (character, since) = self.running;
+        return since.checked_add(self.remaining(character));
+    }
+
+    /// flag returns the player to move if they ran out of time.
+    pub fn flag(&mut self, now: Instant) -> Option<GameCharacter> {
+        if self.deadline().is_none_or(|deadline| now < deadline) {
+            return None;
+        }
+        self.charge(now);
+        return Some(self.running.0);
+    }
+
+    /// times returns the time left of each player. The running clock is counted up to `now`, if any.
+    pub fn times(&self, now: Option<Instant>) -> ClockTimes {
+        let (running, since) = self.running;
+        let left = |character: GameCharacter| {
+            let elapsed = match now {
+                Some(now) if character == running => now.saturating_duration_since(since),
+                _ => Duration::ZERO,
+            };
+            return self
+                .remaining(character)
+                .saturating_sub(elapsed)
+                .as_millis() as u64;
+        };
+        return ClockTimes {
+            x_remaining_ms: left(GameCharacter::X),
+            o_remaining_ms: left(GameCharacter::O),
+        };
+    }
+
+    fn charge(&mut self, now: Instant) {
+        let (character, since) = self.running;
+        let remaining = self.remaining_mut(character);
+        *remaining = remaining.saturating_sub(now.saturating_duration_since(since));
+        self.running.1 = now;
+    }
+
+    fn run(&mut self, character: GameCharacter, now: Instant) {
+        if let ClockSettings::PerMove { move_secs } = self.settings {
+            *self.remaining_mut(character) = Duration::from_secs(move_secs);
+        }
+        self.running = (character, now);
+    }
+
+    fn remaining(&self, character: GameCharacter) -> Duration {
+        return match character {
+            GameCharacter::X => self.x_remaining,
+            GameCharacter::O => self.o_remaining,
+        };
+    }
+
+    fn remaining_mut(&mut self, character: GameCharacter) -> &mut Duration {
+        return match character {
+            GameCharacter::X => &mut self.x_remaining,
+            GameCharacter::O => &mut self.o_remaining,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        return Duration::from_secs(secs);
+    }
+
+    #[test]
+    fn fischer_charges_the_mover_and_adds_the_increment() {
+        let start = Instant::now();
+        let settings = ClockSettings::Fischer {
+            base_secs: 60,
+            increment_secs: 2,
+        };
+        let mut clock = Clock::start(settings, start);
+
+        clock.press(start + secs(10));
+        let times = clock.times(None);
+        assert_eq!(times.x_remaining_ms, 52_000);
+        assert_eq!(times.o_remaining_ms, 60_000);
+
+        clock.press(start + secs(15));
+        let times = clock.times(None);
+        assert_eq!(times.x_remaining_ms, 52_000);
+        assert_eq!(times.o_remaining_ms, 57_000);
+    }
+
+    #[test]
+    fn per_move_resets_the_time_of_each_move() {
+        let start = Instant::now();
+        let mut clock = Clock::start(ClockSettings::PerMove { move_secs: 30 }, start);
+
+        clock.press(start + secs(20));
+        assert_eq!(clock.deadline(), Some(start + secs(50)));
+
+        clock.press(start + secs(45));
+        assert_eq!(clock.deadline(), Some(start + secs(75)));
+        assert_eq!(clock.times(None).x_remaining_ms, 30_000);
+    }
+
+    #[test]
+    fn switch_to_charges_the_running_player_without_increment() {
+        let start = Instant::now();
+        let settings = ClockSettings::Fischer {
+            base_secs: 60,
+            increment_secs: 5,
+        };
+        let mut clock = Clock::start(settings, start);
+        clock.press(start + secs(10));
+
+        // O takes 4 seconds before X's move is taken back, so X is to move again
+        clock.switch_to(GameCharacter::X, start + secs(14));
+        let times = clock.times(None);
+        assert_eq!(times.x_remaining_ms, 55_000);
+        assert_eq!(times.o_remaining_ms, 56_000);
+        assert_eq!(clock.deadline(), Some(start + secs(69)));
+    }
+
+    #[test]
+    fn flag_falls_at_the_deadline() {
+        let start = Instant::now();
+        let mut clock = Clock::start(ClockSettings::PerMove { move_secs: 30 }, start);
+
+        assert_eq!(clock.flag(start + secs(29)), None);
+        assert_eq!(clock.flag(start + secs(30)), Some(GameCharacter::X));
+        assert_eq!(clock.times(None).x_remaining_ms, 0);
+    }
+
+    #[test]
+    fn times_counts_the_running_clock_up_to_now() {
+        let start = Instant::now();
+        let mut clock = Clock::start(ClockSettings::PerMove { move_secs: 30 }, start);
+        clock.press(start + secs(5));
+
+        let times = clock.times(Some(start + secs(12)));
+        assert_eq!(times.x_remaining_ms, 25_000);
+        assert_eq!(times.o_remaining_ms, 23_000);
+    }
+
+    #[test]
+    fn settings_are_bounded() {
+        let fischer = |base_secs, increment_secs| ClockSettings::Fischer {
+            base_secs,
+            increment_secs,
+        };
+        assert!(fischer(1, 0).is_valid());
+        assert!(fischer(MAX_CLOCK_SECS, MAX_CLOCK_SECS).is_valid());
+        assert!(!fischer(0, 2).is_valid());
+        assert!(!fischer(MAX_CLOCK_SECS + 1, 0).is_valid());
+        assert!(!fischer(60, u64::MAX).is_valid());
+        assert!(!ClockSettings::PerMove { move_secs: 0 }.is_valid());
+        assert!(
+            !ClockSettings::PerMove {
+                move_secs: u64::MAX
+            }
+            .is_valid()
+        );
+    }
+}
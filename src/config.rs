@@ -0,0 +1,82 @@
+use std::{env, path::PathBuf, str::FromStr, time::Duration};
+
+/// Config is the server configuration, loaded from environment variables.
+#[derive(Clone)]
+pub struct Config {
+    /// session_secret signs session tokens (`SESSION_SECRET`).
+    /// When it is not set, a random secret is used and tokens do not survive a restart.
+    pub session_secret: String,
+    /// session_ttl is how long a session token is valid (`SESSION_TTL_SECS`, default 1 day).
+    pub session_ttl: Duration,
+    /// accounts_path is the JSON file storing the registered accounts (`ACCOUNTS_PATH`).
+    pub accounts_path: PathBuf,
+    /// forfeit_grace_period is how long a disconnected player has to reconnect
+    /// before forfeiting their running games (`FORFEIT_GRACE_PERIOD_SECS`, default 1 minute).
+    pub forfeit_grace_period: Duration,
+    /// ping_interval is how often WebSockets are pinged (`PING_INTERVAL_SECS`, default 15 seconds).
+    pub ping_interval: Duration,
+    /// idle_timeout is how long a WebSocket may stay silent, pongs included, before it is closed
+    /// (`IDLE_TIMEOUT_SECS`, default 45 seconds). It should be a few times `ping_interval`.
+    pub idle_timeout: Duration,
+    /// room_channel_capacity is how many events a room buffers for its slowest subscriber
+    /// (`ROOM_CHANNEL_CAPACITY`, default 100). Subscribers falling further behind receive a snapshot.
+    pub room_channel_capacity: usize,
+    /// room_reaper_interval is how often stale rooms are looked for
+    /// (`ROOM_REAPER_INTERVAL_SECS`, default 30 seconds).
+    pub room_reaper_interval: Duration,
+    /// finished_room_ttl is how long a room is kept once its game is over
+    /// (`FINISHED_ROOM_TTL_SECS`, default 5 minutes).
+    pub finished_room_ttl: Duration,
+    /// unfilled_room_ttl is how long a room may wait for its players
+    /// (`UNFILLED_ROOM_TTL_SECS`, default 10 minutes).
+    pub unfilled_room_ttl: Duration,
+    /// idle_room_ttl is how long a room may go without any event (`IDLE_ROOM_TTL_SECS`, default 30 minutes).
+    pub idle_room_ttl: Duration,
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        let session_secret = env::var("SESSION_SECRET").unwrap_or_else(|_| {
+            tracing::warn!("SESSION_SECRET is not set, session tokens will not survive a restart");
+            format!("{}{}", uuid::Uuid::new_v4(), uuid::Uuid::new_v4())
+        });
+
+        return Config {
+            session_secret,
+            session_ttl: Duration::from_secs(parse_env("SESSION_TTL_SECS", 24 * 60 * 60)),
+            accounts_path: env::var("ACCOUNTS_PATH")
+                .unwrap_or_else(|_| "accounts.json".to_string())
+                .into(),
+            forfeit_grace_period: Duration::from_secs(parse_env("FORFEIT_GRACE_PERIOD_SECS", 60)),
+            ping_interval: Duration::from_secs(parse_non_zero_env("PING_INTERVAL_SECS", 15)),
+            idle_timeout: Duration::from_secs(parse_env("IDLE_TIMEOUT_SECS", 45)),
+            room_channel_capacity: parse_non_zero_env("ROOM_CHANNEL_CAPACITY", 100),
+            room_reaper_interval: Duration::from_secs(parse_non_zero_env(
+                "ROOM_REAPER_INTERVAL_SECS",
+                30,
+            )),
+            finished_room_ttl: Duration::from_secs(parse_env("FINISHED_ROOM_TTL_SECS", 5 * 60)),
+            unfilled_room_ttl: Duration::from_secs(parse_env("UNFILLED_ROOM_TTL_SECS", 10 * 60)),
+            idle_room_ttl: Duration::from_secs(parse_env("IDLE_ROOM_TTL_SECS", 30 * 60)),
+        };
+    }
+}
+
+fn parse_env<T: FromStr>(name: &str, default: T) -> T {
+    return match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            panic!("{name} must be a valid number, got {value}");
+        }),
+        Err(_) => default,
+    };
+}
+
+/// parse_non_zero_env is `parse_env` for the settings that cannot be zero,
+/// so that a wrong value stops the server at startup instead of panicking while it runs.
+fn parse_non_zero_env<T: FromStr + Default + PartialEq>(name: &str, default: T) -> T {
+    let value = parse_env(name, default);
+    if value == T::default() {
+        panic!("{name} must not be zero");
+    }
+    return value;
+}
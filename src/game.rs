@@ -1,11 +1,21 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
 
-#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+use crate::accounts::AccountStore;
+use crate::clock::{Clock, ClockSettings, ClockTimes};
+use crate::config::Config;
+use crate::metrics::Metrics;
+use crate::session::SessionKeys;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub enum GameCharacter {
     #[serde(alias = "X")]
     X,
@@ -13,79 +23,241 @@ pub enum GameCharacter {
     O,
 }
 
+impl GameCharacter {
+    pub fn opponent(&self) -> GameCharacter {
+        return match self {
+            GameCharacter::X => GameCharacter::O,
+            GameCharacter::O => GameCharacter::X,
+        };
+    }
+}
+
+/// Cell is a square of the board.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Cell {
+    pub row: usize,
+    pub column: usize,
+}
+
+/// LineKind is the direction of a line of the board.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LineKind {
+    Row,
+    Column,
+    /// Diagonal goes from the top left to the bottom right.
+    Diagonal,
+    /// AntiDiagonal goes from the top right to the bottom left.
+    AntiDiagonal,
+}
+
+/// WinningLine is the line filled by the winner, so clients can highlight it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct WinningLine {
+    pub kind: LineKind,
+    /// cells are the cells of the line, in the direction of its kind.
+    pub cells: Vec<Cell>,
+}
+
+/// Board is the grid of a room, as rows of cells.
+pub type Board = Vec<Vec<Option<GameCharacter>>>;
+
+/// step returns the next cell in the direction, unless it is above or left of the board.
+fn step(cell: Cell, row_step: isize, column_step: isize) -> Option<Cell> {
+    return Some(Cell {
+        row: cell.row.checked_add_signed(row_step)?,
+        column: cell.column.checked_add_signed(column_step)?,
+    });
+}
+
+/// MIN_BOARD_SIZE is the fewest rows, columns and cells in a line a room can be created with.
+const MIN_BOARD_SIZE: usize = 3;
+/// MAX_BOARD_SIZE is the most rows and columns a room can be created with.
+const MAX_BOARD_SIZE: usize = 20;
+
+/// HISTORY_CAPACITY is how many of its latest events a room keeps for clients that resume.
+const HISTORY_CAPACITY: usize = 100;
+
+/// Player is a user seated in a room, either a registered account or a guest.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Player {
+    pub user_id: String,
+    pub display_name: String,
+}
+
+/// PlayedMove is a move kept in the history of the running game.
+#[derive(Clone, Copy, Debug)]
+pub struct PlayedMove {
+    pub row: usize,
+    pub column: usize,
+    pub character: GameCharacter,
+}
+
 pub struct Room {
-    x: Option<String>,
-    o: Option<String>,
-    board: [[Option<GameCharacter>; 3]; 3],
+    settings: RoomSettings,
+    x: Option<Player>,
+    o: Option<Player>,
+    board: Board,
     current_turn: Option<GameCharacter>,
     winner: Option<GameCharacter>,
+    /// winning_line is the line filled by the winner, when the game was won by a line.
+    winning_line: Option<WinningLine>,
+    /// clock is set while a game of a room with a time control is played.
+    clock: Option<Clock>,
+    /// moves are the moves of the running game, oldest first.
+    moves: Vec<PlayedMove>,
+    /// undo_requested_by is the player whose takeback request is waiting for the opponent's answer.
+    undo_requested_by: Option<GameCharacter>,
+    /// draw_offered_by is the player whose draw offer is waiting for the opponent's answer.
+    draw_offered_by: Option<GameCharacter>,
+    is_draw_agreed: bool,
+    /// rematch_requested_by is the player whose rematch request is waiting for the opponent's answer.
+    rematch_requested_by: Option<GameCharacter>,
+    /// score is the result of the previous games of the series, without the current one.
+    score: SeriesScore,
+    sender: broadcast::Sender<ServerMessage>,
+    /// seq is the sequence number of the latest event published to this room.
+    seq: u64,
+    history: VecDeque<ServerMessage>,
+    /// connections counts the WebSockets of each user subscribed to this room.
+    connections: HashMap<String, usize>,
+    /// disconnected_since is when each player lost their last WebSocket to this room.
+    disconnected_since: HashMap<String, Instant>,
+    created_at: Instant,
+    /// last_activity_at is when the latest event was published to this room.
+    last_activity_at: Instant,
+}
+
+/// MissedEvents is what a client resuming a room has missed.
+pub enum MissedEvents {
+    /// Replay holds the events published since the client's last event, oldest first.
+    Replay(Vec<ServerMessage>),
+    /// Snapshot is the state of the room, when some of the missed events are no longer kept.
+    Snapshot(Box<RoomSnapshot>),
+}
+
+impl MissedEvents {
+    /// into_messages returns the messages that bring a client up to date, oldest first.
+    pub fn into_messages(self, request_id: &Option<String>) -> Vec<ServerMessage> {
+        return match self {
+            MissedEvents::Replay(events) => events,
+            MissedEvents::Snapshot(snapshot) => vec![ServerMessage::new(
+                ServerEvent::RoomState(*snapshot),
+                request_id.clone(),
+            )],
+        };
+    }
 }
 
 impl Room {
-    pub fn new() -> Room {
+    pub fn new(channel_capacity: usize, settings: RoomSettings) -> Room {
+        let board = settings.empty_board();
         return Room {
+            settings,
             x: None,
             o: None,
-            board: [[None; 3]; 3],
+            board,
             current_turn: None,
             winner: None,
+            winning_line: None,
+            clock: None,
+            moves: Vec::new(),
+            undo_requested_by: None,
+            draw_offered_by: None,
+            is_draw_agreed: false,
+            rematch_requested_by: None,
+            score: SeriesScore::default(),
+            sender: broadcast::channel(channel_capacity).0,
+            seq: 0,
+            history: VecDeque::with_capacity(HISTORY_CAPACITY),
+            connections: HashMap::new(),
+            disconnected_since: HashMap::new(),
+            created_at: Instant::now(),
+            last_activity_at: Instant::now(),
         };
     }
 
-    pub fn join(&mut self, user_id: String) -> Result<GameCharacter, String> {
-        match self.x.clone() {
+    /// subscribe returns a receiver for every event published to this room.
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerMessage> {
+        return self.sender.subscribe();
+    }
+
+    /// broadcast numbers the message with the next sequence number of this room,
+    /// keeps it in the history and publishes it to every connection subscribed to this room.
+    /// It returns the sequence number of the message.
+    pub fn broadcast(&mut self, mut message: ServerMessage) -> u64 {
+        self.seq += 1;
+        self.last_activity_at = Instant::now();
+        message.seq = Some(self.seq);
+        if self.history.len() == HISTORY_CAPACITY {
+            self.history.pop_front();
+        }
+        self.history.push_back(message.clone());
+        // no subscriber is not an error: the message is still kept in the history
+        let _ = self.sender.send(message);
+        return self.seq;
+    }
+
+    /// missed_events returns the events published after `last_seq`.
+    /// It falls back to a snapshot when they are no longer all in the history,
+    /// or when `last_seq` is ahead of this room (e.g. it belongs to an older server run).
+    pub fn missed_events(&self, room_id: String, last_seq: u64) -> MissedEvents {
+        let oldest_kept_seq = self.seq + 1 - self.history.len() as u64;
+        if last_seq > self.seq || last_seq + 1 < oldest_kept_seq {
+            return MissedEvents::Snapshot(Box::new(self.snapshot(room_id)));
+        }
+
+        let events = self
+            .history
+            .iter()
+            .filter(|message| message.seq.is_some_and(|seq| seq > last_seq))
+            .cloned()
+            .collect();
+        return MissedEvents::Replay(events);
+    }
+
+    pub fn join(&mut self, player: Player) -> Result<GameCharacter, ServerError> {
+        match &self.x {
             None => {
-                self.x = Some(user_id);
+                self.x = Some(player);
                 return Ok(GameCharacter::X);
             }
-            Some(assigned_user_id) => {
-                if assigned_user_id == user_id {
+            Some(assigned_player) => {
+                if assigned_player.user_id == player.user_id {
                     return Ok(GameCharacter::X);
                 }
             }
         }
 
-        match self.o.clone() {
+        match &self.o {
             None => {
-                self.o = Some(user_id);
+                self.o = Some(player);
                 return Ok(GameCharacter::O);
             }
-            Some(assigned_user_id) => {
-                if assigned_user_id == user_id {
+            Some(assigned_player) => {
+                if assigned_player.user_id == player.user_id {
                     return Ok(GameCharacter::O);
                 }
             }
         }
 
-        return Err(String::from("Room is already full"));
+        return Err(ServerError::RoomFull);
     }
 
-    pub fn leave(&mut self, user_id: String) -> Result<GameCharacter, String> {
-        let e = Err(String::from("User never joined this room"));
-
-        match self.x.clone() {
-            Some(assigned_user_id) => {
-                if assigned_user_id == user_id {
-                    self.x = None;
-                    return Ok(GameCharacter::X);
-                }
-            }
-            _ => {}
-        }
-
-        match self.o.clone() {
-            Some(assigned_user_id) => {
-                if assigned_user_id == user_id {
-                    self.o = None;
-                    return Ok(GameCharacter::O);
-                } else {
-                    return e;
-                }
-            }
-            None => {
-                return e;
-            }
+    /// leave frees the seat of the user. The pending draw, takeback and rematch requests are dropped,
+    /// since they cannot be answered without both players.
+    pub fn leave(&mut self, user_id: String) -> Result<GameCharacter, ServerError> {
+        let character = self
+            .get_character(&user_id)
+            .ok_or(ServerError::UserNotInRoom)?;
+        match character {
+            GameCharacter::X => self.x = None,
+            GameCharacter::O => self.o = None,
         }
+        self.draw_offered_by = None;
+        self.undo_requested_by = None;
+        self.rematch_requested_by = None;
+        return Ok(character);
     }
 
     pub fn is_full(&self) -> bool {
@@ -98,6 +270,10 @@ impl Room {
 
     pub fn start_game(&mut self) {
         self.current_turn = Option::Some(GameCharacter::X);
+        self.clock = self
+            .settings
+            .clock
+            .map(|settings| Clock::start(settings, Instant::now()));
     }
 
     pub fn has_game_started(&self) -> bool {
@@ -107,35 +283,45 @@ impl Room {
     /// get_character is a function that returns the character of the user.
     pub fn get_character(&self, user_id: &String) -> Option<GameCharacter> {
         let x = self.x.as_ref();
-        if let Some(value) = x {
-            if value == user_id {
-                return Some(GameCharacter::X);
-            }
+        if let Some(value) = x
+            && value.user_id == *user_id
+        {
+            return Some(GameCharacter::X);
         }
 
         let o = self.o.as_ref();
-        if let Some(value) = o {
-            if value == user_id {
-                return Some(GameCharacter::O);
-            }
+        if let Some(value) = o
+            && value.user_id == *user_id
+        {
+            return Some(GameCharacter::O);
         }
 
         return None;
     }
 
-    pub fn get_current_turn(&self) -> Option<GameCharacter> {
-        return self.current_turn;
-    }
-
+    /// register_move places the character of the user on the cell, if the game is running and it is their turn.
+    /// The checks and the move happen together, so two concurrent moves of a player cannot both be placed.
     pub fn register_move(
         &mut self,
+        user_id: &String,
         row: usize,
         column: usize,
-        character: GameCharacter,
-    ) -> Result<[[Option<GameCharacter>; 3]; 3], String> {
-        let square = self.board.get_mut(row).unwrap().get_mut(column).unwrap();
+    ) -> Result<Board, ServerError> {
+        self.check_game_running()?;
+        let character = self
+            .get_character(user_id)
+            .ok_or(ServerError::UserNotInRoom)?;
+        if self.current_turn != Some(character) {
+            return Err(ServerError::NotYourTurn);
+        }
+
+        let square = self
+            .board
+            .get_mut(row)
+            .and_then(|cells| cells.get_mut(column))
+            .ok_or(ServerError::OutOfBounds)?;
         if square.is_some() {
-            return Err(String::from("invalid move"));
+            return Err(ServerError::CellOccupied);
         }
 
         let _ = square.insert(character);
@@ -144,153 +330,1140 @@ impl Room {
         } else {
             self.current_turn = Some(GameCharacter::X);
         }
+        self.moves.push(PlayedMove {
+            row,
+            column,
+            character,
+        });
+        if let Some(clock) = self.clock.as_mut() {
+            clock.press(Instant::now());
+        }
+        // a draw offer or a takeback request only stands until the next move
+        self.draw_offered_by = None;
+        self.undo_requested_by = None;
         return Ok(self.board.clone());
     }
 
-    fn check_winner(&self) -> Option<GameCharacter> {
-        // left -> right
-        for r in 0..=2 {
-            let mut same_char = true;
-            for c in 1..=2 {
-                let prev = self.board[r][c - 1];
-                let curr = self.board[r][c];
-                if prev.is_none() || curr.is_none() || curr.unwrap() != prev.unwrap() {
-                    same_char = false;
-                    break;
+    /// check_winner returns the winner and the line they filled, if any.
+    /// A line is a run of at least `win_length` cells of the same character, and all of its cells are returned.
+    fn check_winner(&self) -> Option<(GameCharacter, WinningLine)> {
+        let directions = [
+            (LineKind::Row, 0, 1),
+            (LineKind::Column, 1, 0),
+            (LineKind::Diagonal, 1, 1),
+            (LineKind::AntiDiagonal, 1, -1),
+        ];
+        for (row, cells) in self.board.iter().enumerate() {
+            for (column, cell) in cells.iter().enumerate() {
+                let Some(character) = *cell else {
+                    continue;
+                };
+                let start = Cell { row, column };
+                for (kind, row_step, column_step) in directions {
+                    // a run is only followed from its first cell
+                    let previous = step(start, -row_step, -column_step);
+                    if previous
+                        .is_some_and(|previous| self.character_at(previous) == Some(character))
+                    {
+                        continue;
+                    }
+
+                    let mut cells = vec![start];
+                    while let Some(next) = step(cells[cells.len() - 1], row_step, column_step)
+                        && self.character_at(next) == Some(character)
+                    {
+                        cells.push(next);
+                    }
+                    if cells.len() >= self.settings.win_length {
+                        return Some((character, WinningLine { kind, cells }));
+                    }
                 }
             }
-            if same_char {
-                return self.board[r][0];
-            }
         }
+        return None;
+    }
 
-        // top -> bottom
-        for c in 0..=2 {
-            let mut same_char = true;
-            for r in 1..=2 {
-                let prev = self.board[r - 1][c];
-                let curr = self.board[r][c];
-                if prev.is_none() || curr.is_none() || curr.unwrap() != prev.unwrap() {
-                    same_char = false;
-                    break;
-                }
-            }
-            if same_char {
-                return self.board[0][c];
-            }
+    fn character_at(&self, cell: Cell) -> Option<GameCharacter> {
+        return self
+            .board
+            .get(cell.row)
+            .and_then(|cells| cells.get(cell.column))
+            .copied()
+            .flatten();
+    }
+
+    /// check_and_set_winner records the winner and their line, if any.
+    pub fn check_and_set_winner(&mut self) -> Option<(GameCharacter, WinningLine)> {
+        let winner = self.check_winner();
+        self.winner = winner.as_ref().map(|(character, _)| *character);
+        self.winning_line = winner.as_ref().map(|(_, line)| line.clone());
+        return winner;
+    }
+
+    pub fn get_player_from_character(&self, character: GameCharacter) -> Option<Player> {
+        return match character {
+            GameCharacter::X => self.x.clone(),
+            GameCharacter::O => self.o.clone(),
+        };
+    }
+
+    pub fn has_game_finished(&self) -> bool {
+        return self.winner.is_some();
+    }
+
+    /// close_reason returns why this room should be closed, if its time is up.
+    /// A TTL of zero never expires. A game whose clock is still running is not idle:
+    /// the player to move may take all of their time.
+    pub fn close_reason(&self, config: &Config) -> Option<CloseReason> {
+        let is_expired = |since: Instant, ttl: Duration| !ttl.is_zero() && since.elapsed() >= ttl;
+        if self.is_game_over() && is_expired(self.last_activity_at, config.finished_room_ttl) {
+            return Some(CloseReason::Finished);
+        }
+        if !self.has_game_started() && is_expired(self.created_at, config.unfilled_room_ttl) {
+            return Some(CloseReason::Unfilled);
         }
+        let is_clock_running = self
+            .clock_deadline()
+            .is_some_and(|deadline| deadline > Instant::now());
+        if !is_clock_running && is_expired(self.last_activity_at, config.idle_room_ttl) {
+            return Some(CloseReason::Idle);
+        }
+        return None;
+    }
 
-        // diagonal: top left -> bottom right
-        let mut same_char = true;
-        for i in 1..=2 {
-            let prev = self.board[i - 1][i - 1];
-            let curr = self.board[i][i];
-            if prev.is_none() || curr.is_none() || curr.unwrap() != prev.unwrap() {
-                same_char = false;
-                break;
-            }
+    /// connect counts a new WebSocket of the user.
+    /// It returns true when the user is a player who had been disconnected.
+    pub fn connect(&mut self, user_id: &String) -> bool {
+        *self.connections.entry(user_id.clone()).or_insert(0) += 1;
+        return self.disconnected_since.remove(user_id).is_some();
+    }
+
+    /// disconnect forgets a WebSocket of the user.
+    /// It returns when the user got disconnected, if they are a player left without any WebSocket.
+    pub fn disconnect(&mut self, user_id: &String) -> Option<Instant> {
+        let connection_count = self.connections.get_mut(user_id)?;
+        *connection_count -= 1;
+        if *connection_count > 0 {
+            return None;
         }
-        if same_char {
-            return self.board[0][0];
+        self.connections.remove(user_id);
+
+        self.get_character(user_id)?;
+        let now = Instant::now();
+        self.disconnected_since.insert(user_id.clone(), now);
+        return Some(now);
+    }
+
+    /// forfeit ends the running game in favor of the opponent of the user,
+    /// if the user is still disconnected since `since`. It returns the winner.
+    pub fn forfeit(&mut self, user_id: &String, since: Instant) -> Option<(GameCharacter, Player)> {
+        if self.disconnected_since.get(user_id) != Some(&since)
+            || !self.has_game_started()
+            || self.is_game_over()
+        {
+            return None;
         }
+        return self.award_opponent(user_id);
+    }
 
-        // diagonal: top right -> bottom left
-        let mut same_char = true;
-        let mut r = 1;
-        let mut c = 1;
-        while r <= 2 {
-            let prev = self.board[r - 1][c + 1];
-            let curr = self.board[r][c];
-            if prev.is_none() || curr.is_none() || curr.unwrap() != prev.unwrap() {
-                same_char = false;
-                break;
-            }
+    /// time_out ends the running game in favor of the opponent of the player to move,
+    /// if they ran out of time. It returns the user who ran out of time, and the winner.
+    pub fn time_out(&mut self) -> Option<(String, GameCharacter, Player)> {
+        if !self.has_game_started() || self.is_game_over() {
+            return None;
+        }
+        let character = self.clock.as_mut()?.flag(Instant::now())?;
+        let user_id = self.get_player_from_character(character)?.user_id;
+        let (winner_character, winner) = self.award_opponent(&user_id)?;
+        return Some((user_id, winner_character, winner));
+    }
+
+    /// clock_deadline is when the player to move runs out of time, while a game with a clock is running.
+    pub fn clock_deadline(&self) -> Option<Instant> {
+        if !self.has_game_started() || self.is_game_over() {
+            return None;
+        }
+        return self.clock.as_ref().and_then(|clock| clock.deadline());
+    }
+
+    /// clock_times returns the time left of each player. The clocks stop when the game is over.
+    pub fn clock_times(&self) -> Option<ClockTimes> {
+        let now = if self.is_game_over() {
+            None
+        } else {
+            Some(Instant::now())
+        };
+        return self.clock.as_ref().map(|clock| clock.times(now));
+    }
+
+    /// resign ends the running game in favor of the opponent of the user. It returns the winner.
+    pub fn resign(&mut self, user_id: &String) -> Result<(GameCharacter, Player), ServerError> {
+        self.check_game_running()?;
+        return self
+            .award_opponent(user_id)
+            .ok_or(ServerError::UserNotInRoom);
+    }
+
+    /// offer_draw records the draw offer of the user, until the opponent answers it or a move is made.
+    pub fn offer_draw(&mut self, user_id: &String) -> Result<(), ServerError> {
+        self.check_game_running()?;
+        let character = self
+            .get_character(user_id)
+            .ok_or(ServerError::UserNotInRoom)?;
+        if self.draw_offered_by.is_some() {
+            return Err(ServerError::DrawAlreadyOffered);
+        }
+        self.draw_offered_by = Some(character);
+        return Ok(());
+    }
+
+    /// accept_draw ends the running game with a draw, if the opponent of the user offered it.
+    pub fn accept_draw(&mut self, user_id: &String) -> Result<(), ServerError> {
+        self.take_opponent_draw_offer(user_id)?;
+        self.is_draw_agreed = true;
+        return Ok(());
+    }
+
+    /// decline_draw turns down the draw offered by the opponent of the user.
+    pub fn decline_draw(&mut self, user_id: &String) -> Result<(), ServerError> {
+        return self.take_opponent_draw_offer(user_id);
+    }
 
-            if c == 0 {
-                break;
+    fn take_opponent_draw_offer(&mut self, user_id: &String) -> Result<(), ServerError> {
+        self.check_game_running()?;
+        let character = self
+            .get_character(user_id)
+            .ok_or(ServerError::UserNotInRoom)?;
+        return match self.draw_offered_by {
+            Some(offered_by) if offered_by != character => {
+                self.draw_offered_by = None;
+                Ok(())
             }
+            _ => Err(ServerError::NoDrawOffer),
+        };
+    }
 
-            r += 1;
-            c -= 1;
+    /// request_undo records the request of the user to take back their last move.
+    pub fn request_undo(&mut self, user_id: &String) -> Result<(), ServerError> {
+        self.check_game_running()?;
+        if !self.settings.allow_takebacks {
+            return Err(ServerError::TakebacksDisabled);
         }
-        if same_char {
-            return self.board[0][2];
+        let character = self
+            .get_character(user_id)
+            .ok_or(ServerError::UserNotInRoom)?;
+        if self.undo_requested_by.is_some() {
+            return Err(ServerError::UndoAlreadyRequested);
         }
+        if self
+            .moves
+            .last()
+            .is_none_or(|last| last.character != character)
+        {
+            return Err(ServerError::NoMoveToUndo);
+        }
+        self.undo_requested_by = Some(character);
+        return Ok(());
+    }
 
-        return None;
+    /// accept_undo reverts the last move, if the opponent of the user requested it.
+    /// The opponent gets the turn back.
+    pub fn accept_undo(&mut self, user_id: &String) -> Result<(PlayedMove, Board), ServerError> {
+        self.take_opponent_undo_request(user_id)?;
+        let last = self.moves.pop().ok_or(ServerError::NoMoveToUndo)?;
+        self.board[last.row][last.column] = None;
+        self.current_turn = Some(last.character);
+        if let Some(clock) = self.clock.as_mut() {
+            clock.switch_to(last.character, Instant::now());
+        }
+        return Ok((last, self.board.clone()));
     }
 
-    pub fn check_and_set_winner(&mut self) -> Option<GameCharacter> {
-        let winner = self.check_winner();
-        self.winner = winner;
-        return winner;
+    /// decline_undo turns down the takeback requested by the opponent of the user.
+    pub fn decline_undo(&mut self, user_id: &String) -> Result<(), ServerError> {
+        return self.take_opponent_undo_request(user_id);
     }
 
-    pub fn get_user_id_from_character(&self, character: GameCharacter) -> Option<(char, String)> {
-        return match character {
-            GameCharacter::X => match self.x.clone() {
-                Some(user_id) => Some(('x', user_id)),
-                _ => None,
-            },
-            GameCharacter::O => match self.o.clone() {
-                Some(user_id) => Some(('o', user_id)),
-                _ => None,
-            },
+    fn take_opponent_undo_request(&mut self, user_id: &String) -> Result<(), ServerError> {
+        self.check_game_running()?;
+        let character = self
+            .get_character(user_id)
+            .ok_or(ServerError::UserNotInRoom)?;
+        return match self.undo_requested_by {
+            Some(requested_by) if requested_by != character => {
+                self.undo_requested_by = None;
+                Ok(())
+            }
+            _ => Err(ServerError::NoUndoRequest),
         };
     }
 
-    pub fn has_game_finished(&self) -> bool {
-        return self.winner.is_some();
+    /// request_rematch records the rematch request of the user, once the game is over.
+    pub fn request_rematch(&mut self, user_id: &String) -> Result<(), ServerError> {
+        if !self.is_game_over() {
+            return Err(ServerError::GameNotFinished);
+        }
+        let character = self
+            .get_character(user_id)
+            .ok_or(ServerError::UserNotInRoom)?;
+        if self.rematch_requested_by.is_some() {
+            return Err(ServerError::RematchAlreadyRequested);
+        }
+        self.rematch_requested_by = Some(character);
+        return Ok(());
+    }
+
+    /// accept_rematch starts a new game, if the opponent of the user requested it.
+    /// The players swap sides, so the other player makes the first move.
+    pub fn accept_rematch(&mut self, user_id: &String) -> Result<(), ServerError> {
+        let character = self
+            .get_character(user_id)
+            .ok_or(ServerError::UserNotInRoom)?;
+        match self.rematch_requested_by {
+            Some(requested_by)
+                if requested_by != character && self.is_game_over() && self.is_full() => {}
+            _ => return Err(ServerError::NoRematchRequest),
+        }
+
+        self.score = self.series_score();
+        std::mem::swap(&mut self.x, &mut self.o);
+        self.board = self.settings.empty_board();
+        self.winner = None;
+        self.winning_line = None;
+        self.moves.clear();
+        self.undo_requested_by = None;
+        self.draw_offered_by = None;
+        self.is_draw_agreed = false;
+        self.rematch_requested_by = None;
+        self.start_game();
+        return Ok(());
+    }
+
+    /// series_score is the result of every game played in this room, the current one included once it is over.
+    pub fn series_score(&self) -> SeriesScore {
+        let mut score = self.score.clone();
+        if let Some(winner) = self.winner.and_then(|c| self.get_player_from_character(c)) {
+            *score.wins.entry(winner.user_id).or_insert(0) += 1;
+        } else if self.is_game_draw() {
+            score.draws += 1;
+        }
+        return score;
+    }
+
+    fn check_game_running(&self) -> Result<(), ServerError> {
+        if !self.has_game_started() {
+            return Err(ServerError::GameNotStarted);
+        }
+        if self.is_game_over() {
+            return Err(ServerError::GameAlreadyFinished);
+        }
+        return Ok(());
+    }
+
+    fn award_opponent(&mut self, user_id: &String) -> Option<(GameCharacter, Player)> {
+        let winner_character = self.get_character(user_id)?.opponent();
+        let winner = self.get_player_from_character(winner_character)?;
+        self.winner = Some(winner_character);
+        return Some((winner_character, winner));
+    }
+
+    /// is_game_over tells whether the game has ended, with a winner or a draw.
+    pub fn is_game_over(&self) -> bool {
+        return self.has_game_finished() || self.is_game_draw();
+    }
+
+    pub fn snapshot(&self, room_id: String) -> RoomSnapshot {
+        return RoomSnapshot {
+            room_id,
+            x: self.x.as_ref().map(|x| x.user_id.clone()),
+            o: self.o.as_ref().map(|o| o.user_id.clone()),
+            x_display_name: self.x.as_ref().map(|x| x.display_name.clone()),
+            o_display_name: self.o.as_ref().map(|o| o.display_name.clone()),
+            board: self.board.clone(),
+            current_turn: self.current_turn,
+            winner: self.winner,
+            winning_line: self.winning_line.clone(),
+            is_draw: self.is_game_draw(),
+            score: self.series_score(),
+            settings: self.settings.clone(),
+            clocks: self.clock_times(),
+            last_seq: self.seq,
+        };
     }
 
     pub fn is_game_draw(&self) -> bool {
         if self.winner.is_some() {
             return false;
         }
+        if self.is_draw_agreed {
+            return true;
+        }
 
-        let mut empty_cells = 9;
-        for row in 0..=2 {
-            for column in 0..=2 {
-                let cell = self.board[row][column];
-                if cell.is_some() {
-                    empty_cells -= 1;
-                }
-            }
+        return self.board.iter().flatten().all(Option::is_some);
+    }
+}
+
+/// RoomSnapshot is the current state of a room, as seen by clients.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RoomSnapshot {
+    pub room_id: String,
+    pub x: Option<String>,
+    pub o: Option<String>,
+    pub x_display_name: Option<String>,
+    pub o_display_name: Option<String>,
+    pub board: Board,
+    pub current_turn: Option<GameCharacter>,
+    pub winner: Option<GameCharacter>,
+    pub winning_line: Option<WinningLine>,
+    pub is_draw: bool,
+    pub score: SeriesScore,
+    pub settings: RoomSettings,
+    pub clocks: Option<ClockTimes>,
+    /// last_seq is the sequence number of the latest event published to the room.
+    pub last_seq: u64,
+}
+
+/// RoomSettings are chosen by the player who creates the room.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct RoomSettings {
+    /// allow_takebacks lets a player ask the opponent to undo their last move. It defaults to true.
+    pub allow_takebacks: bool,
+    /// clock is the time control of the games. Games have no time limit without it.
+    pub clock: Option<ClockSettings>,
+    /// rows is the height of the board. It defaults to 3.
+    pub rows: usize,
+    /// columns is the width of the board. It defaults to 3.
+    pub columns: usize,
+    /// win_length is how many cells in a row, column or diagonal win the game. It defaults to 3.
+    pub win_length: usize,
+}
+
+impl RoomSettings {
+    /// validate rejects boards that are too small or too large, and lines that do not fit in the board.
+    pub fn validate(&self) -> Result<(), ServerError> {
+        let board_sizes = MIN_BOARD_SIZE..=MAX_BOARD_SIZE;
+        if !board_sizes.contains(&self.rows)
+            || !board_sizes.contains(&self.columns)
+            || self.win_length < MIN_BOARD_SIZE
+            || self.win_length > self.rows.max(self.columns)
+        {
+            return Err(ServerError::InvalidSettings);
+        }
+        if self.clock.is_some_and(|clock| !clock.is_valid()) {
+            return Err(ServerError::InvalidSettings);
         }
+        return Ok(());
+    }
+
+    pub fn empty_board(&self) -> Board {
+        return vec![vec![None; self.columns]; self.rows];
+    }
+}
 
-        return empty_cells == 0;
+impl Default for RoomSettings {
+    fn default() -> RoomSettings {
+        return RoomSettings {
+            allow_takebacks: true,
+            clock: None,
+            rows: 3,
+            columns: 3,
+            win_length: 3,
+        };
     }
 }
 
+/// SeriesScore is the result of the games played in a room, rematches included.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct SeriesScore {
+    /// wins counts the games won by each player, by user_id.
+    pub wins: BTreeMap<String, u32>,
+    pub draws: u32,
+}
+
 #[derive(Clone)]
 pub struct AppState {
     pub rooms: Arc<Mutex<HashMap<String, Room>>>,
-    pub sender: tokio::sync::broadcast::Sender<String>,
+    pub config: Arc<Config>,
+    pub session_keys: Arc<SessionKeys>,
+    pub accounts: Arc<AccountStore>,
+    pub metrics: Arc<Metrics>,
 }
 
 impl AppState {
-    pub fn new() -> AppState {
+    pub fn new(config: Config) -> AppState {
         return AppState {
             rooms: Arc::new(Mutex::new(HashMap::new())),
-            sender: tokio::sync::broadcast::channel(100).0,
+            session_keys: Arc::new(SessionKeys::new(config.session_secret.as_bytes())),
+            accounts: Arc::new(AccountStore::load(config.accounts_path.clone())),
+            metrics: Arc::new(Metrics::default()),
+            config: Arc::new(config),
         };
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct HelloParams {
+    pub version: u32,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct CreateParams {
+    /// user_id is optional. When it is sent, it must match the user of the session.
+    pub user_id: Option<String>,
+    /// settings are optional. Missing settings take their default value.
+    #[serde(default)]
+    pub settings: RoomSettings,
+}
+
+/// RoomUserParams are the params of the commands that only need the room and the user,
+/// e.g. `join`, `leave`, `resign`, or answering a draw offer.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct RoomUserParams {
+    pub room_id: String,
+    /// user_id is optional. When it is sent, it must match the user of the session.
+    pub user_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct StateParams {
+    pub room_id: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ResumeParams {
+    pub room_id: String,
+    /// last_seq is the `seq` of the latest event of the room the client has seen, or 0 if none.
+    pub last_seq: u64,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct MoveParams {
+    pub room_id: String,
+    /// user_id is optional. When it is sent, it must match the user of the session.
+    pub user_id: Option<String>,
+    pub row: usize,
+    pub column: usize,
+}
+
+/// CommandType is a command sent by a client, tagged by the `command` field.
+/// Each command carries its own typed `params`, e.g.
+/// `{"command": "move", "params": {"room_id": "...", "user_id": "...", "row": 0, "column": 2}}`.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "command", rename_all = "snake_case")]
 pub enum CommandType {
-    #[serde(alias = "create")]
-    Create,
-    #[serde(alias = "join")]
-    Join,
-    #[serde(alias = "leave")]
-    Leave,
-    #[serde(alias = "move")]
-    Move,
+    #[serde(alias = "Hello")]
+    Hello { params: HelloParams },
+    #[serde(alias = "Create")]
+    Create { params: CreateParams },
+    #[serde(alias = "Join")]
+    Join { params: RoomUserParams },
+    #[serde(alias = "Leave")]
+    Leave { params: RoomUserParams },
+    #[serde(alias = "Move")]
+    Move { params: MoveParams },
+    #[serde(alias = "State", alias = "get_room")]
+    State { params: StateParams },
+    #[serde(alias = "Resume")]
+    Resume { params: ResumeParams },
+    #[serde(alias = "Resign")]
+    Resign { params: RoomUserParams },
+    #[serde(alias = "OfferDraw")]
+    OfferDraw { params: RoomUserParams },
+    #[serde(alias = "AcceptDraw")]
+    AcceptDraw { params: RoomUserParams },
+    #[serde(alias = "DeclineDraw")]
+    DeclineDraw { params: RoomUserParams },
+    #[serde(alias = "RequestUndo")]
+    RequestUndo { params: RoomUserParams },
+    #[serde(alias = "AcceptUndo")]
+    AcceptUndo { params: RoomUserParams },
+    #[serde(alias = "DeclineUndo")]
+    DeclineUndo { params: RoomUserParams },
+    #[serde(alias = "RequestRematch")]
+    RequestRematch { params: RoomUserParams },
+    #[serde(alias = "AcceptRematch")]
+    AcceptRematch { params: RoomUserParams },
 }
 
-#[derive(Serialize, Deserialize)]
+impl CommandType {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            CommandType::Hello { .. } => "hello",
+            CommandType::Create { .. } => "create",
+            CommandType::Join { .. } => "join",
+            CommandType::Leave { .. } => "leave",
+            CommandType::Move { .. } => "move",
+            CommandType::State { .. } => "state",
+            CommandType::Resume { .. } => "resume",
+            CommandType::Resign { .. } => "resign",
+            CommandType::OfferDraw { .. } => "offer_draw",
+            CommandType::AcceptDraw { .. } => "accept_draw",
+            CommandType::DeclineDraw { .. } => "decline_draw",
+            CommandType::RequestUndo { .. } => "request_undo",
+            CommandType::AcceptUndo { .. } => "accept_undo",
+            CommandType::DeclineUndo { .. } => "decline_undo",
+            CommandType::RequestRematch { .. } => "request_rematch",
+            CommandType::AcceptRematch { .. } => "accept_rematch",
+        };
+    }
+
+    pub fn room_id(&self) -> Option<String> {
+        return match self {
+            CommandType::Hello { .. } => None,
+            CommandType::Create { .. } => None,
+            CommandType::Join { params } => Some(params.room_id.clone()),
+            CommandType::Leave { params } => Some(params.room_id.clone()),
+            CommandType::Move { params } => Some(params.room_id.clone()),
+            CommandType::State { params } => Some(params.room_id.clone()),
+            CommandType::Resume { params } => Some(params.room_id.clone()),
+            CommandType::Resign { params } => Some(params.room_id.clone()),
+            CommandType::OfferDraw { params }
+            | CommandType::AcceptDraw { params }
+            | CommandType::DeclineDraw { params } => Some(params.room_id.clone()),
+            CommandType::RequestUndo { params }
+            | CommandType::AcceptUndo { params }
+            | CommandType::DeclineUndo { params } => Some(params.room_id.clone()),
+            CommandType::RequestRematch { params } | CommandType::AcceptRematch { params } => {
+                Some(params.room_id.clone())
+            }
+        };
+    }
+
+    pub fn user_id(&self) -> Option<String> {
+        return match self {
+            CommandType::Hello { .. } => None,
+            CommandType::Create { params } => params.user_id.clone(),
+            CommandType::Join { params } => params.user_id.clone(),
+            CommandType::Leave { params } => params.user_id.clone(),
+            CommandType::Move { params } => params.user_id.clone(),
+            CommandType::State { .. } => None,
+            CommandType::Resume { .. } => None,
+            CommandType::Resign { params } => params.user_id.clone(),
+            CommandType::OfferDraw { params }
+            | CommandType::AcceptDraw { params }
+            | CommandType::DeclineDraw { params } => params.user_id.clone(),
+            CommandType::RequestUndo { params }
+            | CommandType::AcceptUndo { params }
+            | CommandType::DeclineUndo { params } => params.user_id.clone(),
+            CommandType::RequestRematch { params } | CommandType::AcceptRematch { params } => {
+                params.user_id.clone()
+            }
+        };
+    }
+
+    /// validate rejects commands whose ids are empty.
+    pub fn validate(&self) -> Result<(), ServerError> {
+        let room_id_is_empty = self.room_id().is_some_and(|room_id| room_id.is_empty());
+        let user_id_is_empty = self.user_id().is_some_and(|user_id| user_id.is_empty());
+        if room_id_is_empty || user_id_is_empty {
+            return Err(ServerError::InvalidMessage);
+        }
+        return Ok(());
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct WebSocketMessage {
+    /// request_id is an optional id chosen by the client.
+    /// It is echoed on every event caused by this message, and the message is acknowledged with `ACK` or `NACK`.
+    pub request_id: Option<String>,
+    #[serde(flatten)]
     pub command: CommandType,
-    pub params: Option<HashMap<String, String>>,
+}
+
+/// ServerError is the machine-readable reason a command was rejected.
+/// The serialized code is stable; the message is only meant for humans.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ServerError {
+    InvalidMessage,
+    InvalidSettings,
+    UnsupportedVersion,
+    VersionAlreadyNegotiated,
+    Unauthenticated,
+    IdentityMismatch,
+    UsernameTaken,
+    InvalidCredentials,
+    RoomNotFound,
+    RoomFull,
+    UserNotInRoom,
+    GameNotStarted,
+    GameAlreadyStarted,
+    GameAlreadyFinished,
+    NotYourTurn,
+    OutOfBounds,
+    CellOccupied,
+    DrawAlreadyOffered,
+    NoDrawOffer,
+    TakebacksDisabled,
+    NoMoveToUndo,
+    UndoAlreadyRequested,
+    NoUndoRequest,
+    GameNotFinished,
+    RematchAlreadyRequested,
+    NoRematchRequest,
+    InternalError,
+}
+
+impl ServerError {
+    pub fn message(&self) -> &'static str {
+        return match self {
+            ServerError::Unauthenticated => "Session token is missing, invalid or expired",
+            ServerError::IdentityMismatch => "user_id does not match the user of the session",
+            ServerError::UsernameTaken => "Username is already taken",
+            ServerError::InvalidCredentials => "Username or password is wrong",
+            ServerError::InvalidMessage => "Message is malformed",
+            ServerError::InvalidSettings => "Room settings are out of range",
+            ServerError::UnsupportedVersion => "Protocol version is not supported",
+            ServerError::VersionAlreadyNegotiated => {
+                "Protocol version can only be negotiated before any other command"
+            }
+            ServerError::RoomNotFound => "Room does not exist!",
+            ServerError::RoomFull => "Room is already full",
+            ServerError::UserNotInRoom => "User never joined this room",
+            ServerError::GameNotStarted => "Game has not started yet!",
+            ServerError::GameAlreadyStarted => "Game has already started!",
+            ServerError::GameAlreadyFinished => "Game has already finished!",
+            ServerError::NotYourTurn => "It is not your turn",
+            ServerError::OutOfBounds => "Cell is outside of the board",
+            ServerError::CellOccupied => "Cell is already occupied",
+            ServerError::DrawAlreadyOffered => "A draw has already been offered",
+            ServerError::NoDrawOffer => "The opponent has not offered a draw",
+            ServerError::TakebacksDisabled => "Takebacks are disabled in this room",
+            ServerError::NoMoveToUndo => "Your last move is not the last move of the game",
+            ServerError::UndoAlreadyRequested => "A takeback has already been requested",
+            ServerError::NoUndoRequest => "The opponent has not requested a takeback",
+            ServerError::GameNotFinished => "Game has not finished yet!",
+            ServerError::RematchAlreadyRequested => "A rematch has already been requested",
+            ServerError::NoRematchRequest => "The opponent has not requested a rematch",
+            ServerError::InternalError => "Internal server error",
+        };
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{}", self.message());
+    }
+}
+
+/// FinishReason is why a game has a winner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// Line is a row, column or diagonal filled by the winner.
+    Line,
+    /// Forfeit is the opponent staying disconnected longer than the grace period.
+    Forfeit,
+    /// Resignation is the opponent resigning, or leaving the room during the game.
+    Resignation,
+    /// Timeout is the opponent running out of time.
+    Timeout,
+}
+
+/// DrawReason is why a game ended without a winner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DrawReason {
+    /// BoardFull is every cell taken without a line.
+    BoardFull,
+    /// Agreement is a draw offer accepted by the opponent.
+    Agreement,
+}
+
+/// CloseReason is why the server closed a room.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// Finished is a game that ended longer than the finished room TTL ago.
+    Finished,
+    /// Unfilled is a room still waiting for players after the unfilled room TTL.
+    Unfilled,
+    /// Idle is a room without any event for the idle room TTL.
+    Idle,
+}
+
+/// ServerEvent is every message the server sends to clients.
+/// It is serialized with its name in the `event` field, e.g. `{"event": "ROOM_CREATED", ...}`.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "event", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ServerEvent {
+    /// Welcome confirms the protocol version requested with `hello`.
+    Welcome {
+        version: u32,
+    },
+    RoomCreated {
+        room_id: String,
+        user_id: String,
+        settings: RoomSettings,
+    },
+    RoomJoined {
+        room_id: String,
+        user_id: String,
+        display_name: String,
+        character: GameCharacter,
+    },
+    RoomLeft {
+        room_id: String,
+        user_id: String,
+        character: GameCharacter,
+    },
+    GameStarted {
+        room_id: String,
+    },
+    MoveRegistered {
+        room_id: String,
+        user_id: String,
+        board_after_move: Board,
+        /// clocks is the time left of each player, in rooms with a clock.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        clocks: Option<ClockTimes>,
+    },
+    GameFinished {
+        room_id: String,
+        user_id: String,
+        winner_user_id: String,
+        winner_display_name: String,
+        winner_character: GameCharacter,
+        reason: FinishReason,
+        /// winning_line is the line filled by the winner, when the `reason` is `line`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        winning_line: Option<WinningLine>,
+    },
+    GameDrawn {
+        room_id: String,
+        reason: DrawReason,
+    },
+    DrawOffered {
+        room_id: String,
+        user_id: String,
+    },
+    DrawDeclined {
+        room_id: String,
+        user_id: String,
+    },
+    UndoRequested {
+        room_id: String,
+        user_id: String,
+    },
+    /// MoveUndone is the last move taken back, and the turn given back to the player who made it.
+    MoveUndone {
+        room_id: String,
+        user_id: String,
+        row: usize,
+        column: usize,
+        board_after_undo: Board,
+        current_turn: GameCharacter,
+    },
+    UndoDeclined {
+        room_id: String,
+        user_id: String,
+    },
+    RematchRequested {
+        room_id: String,
+        user_id: String,
+    },
+    /// RematchStarted is a new game in the same room, with the players on swapped sides.
+    RematchStarted {
+        room_id: String,
+        x: String,
+        o: String,
+        score: SeriesScore,
+    },
+    /// PlayerDisconnected is sent when a player has no WebSocket to the room anymore.
+    /// A running game is forfeited if they do not reconnect within the grace period.
+    PlayerDisconnected {
+        room_id: String,
+        user_id: String,
+        grace_period_secs: u64,
+    },
+    PlayerReconnected {
+        room_id: String,
+        user_id: String,
+    },
+    /// RoomClosed is the last event of a room, sent before the server removes it.
+    RoomClosed {
+        room_id: String,
+        reason: CloseReason,
+    },
+    /// RoomState is the reply to `state`, so clients can render a room without replaying its events.
+    RoomState(RoomSnapshot),
+    /// Resumed is the reply to `resume`. It is followed by the missed events,
+    /// or by a `ROOM_STATE` when `is_snapshot` is set.
+    Resumed {
+        room_id: String,
+        /// last_seq is the sequence number of the latest event the client is brought up to.
+        last_seq: u64,
+        is_snapshot: bool,
+    },
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        room_id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        user_id: Option<String>,
+        error: ServerError,
+        message: String,
+    },
+    /// Ack is sent to the caller when a command with a `request_id` succeeded.
+    Ack {
+        command: String,
+    },
+    /// Nack is sent to the caller when a command with a `request_id` has been rejected.
+    Nack {
+        error: ServerError,
+        message: String,
+    },
+}
+
+impl ServerEvent {
+    pub fn error(
+        room_id: Option<String>,
+        user_id: Option<String>,
+        error: ServerError,
+    ) -> ServerEvent {
+        return ServerEvent::Error {
+            room_id,
+            user_id,
+            error,
+            message: error.message().to_string(),
+        };
+    }
+}
+
+/// ServerMessage is the envelope around every event sent to a client.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ServerMessage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// seq numbers the events published to a room, starting from 1.
+    /// Clients send the latest one they have seen with `resume`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(flatten)]
+    pub event: ServerEvent,
+}
+
+impl ServerMessage {
+    pub fn new(event: ServerEvent, request_id: Option<String>) -> ServerMessage {
+        return ServerMessage {
+            request_id,
+            seq: None,
+            event,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(rows: usize, columns: usize, win_length: usize) -> RoomSettings {
+        return RoomSettings {
+            rows,
+            columns,
+            win_length,
+            ..RoomSettings::default()
+        };
+    }
+
+    /// room returns a room whose board has `character` on each of `cells`.
+    fn room(settings: RoomSettings, character: GameCharacter, cells: &[(usize, usize)]) -> Room {
+        let mut room = Room::new(16, settings);
+        for &(row, column) in cells {
+            room.board[row][column] = Some(character);
+        }
+        return room;
+    }
+
+    fn cells(cells: &[(usize, usize)]) -> Vec<Cell> {
+        return cells
+            .iter()
+            .map(|&(row, column)| Cell { row, column })
+            .collect();
+    }
+
+    fn assert_win(room: &Room, character: GameCharacter, kind: LineKind, line: &[(usize, usize)]) {
+        let winning_line = WinningLine {
+            kind,
+            cells: cells(line),
+        };
+        assert_eq!(room.check_winner(), Some((character, winning_line)));
+    }
+
+    #[test]
+    fn finds_every_kind_of_line_on_3x3() {
+        let lines = [
+            (LineKind::Row, [(1, 0), (1, 1), (1, 2)]),
+            (LineKind::Column, [(0, 2), (1, 2), (2, 2)]),
+            (LineKind::Diagonal, [(0, 0), (1, 1), (2, 2)]),
+            (LineKind::AntiDiagonal, [(0, 2), (1, 1), (2, 0)]),
+        ];
+        for (kind, line) in lines {
+            let room = room(settings(3, 3, 3), GameCharacter::O, &line);
+            assert_win(&room, GameCharacter::O, kind, &line);
+        }
+    }
+
+    #[test]
+    fn finds_every_kind_of_line_on_a_non_square_board() {
+        let lines = [
+            (LineKind::Row, vec![(4, 1), (4, 2), (4, 3), (4, 4)]),
+            (LineKind::Column, vec![(2, 0), (3, 0), (4, 0), (5, 0)]),
+            (LineKind::Diagonal, vec![(0, 1), (1, 2), (2, 3), (3, 4)]),
+            (LineKind::AntiDiagonal, vec![(2, 4), (3, 3), (4, 2), (5, 1)]),
+        ];
+        for (kind, line) in lines {
+            let room = room(settings(6, 5, 4), GameCharacter::X, &line);
+            assert_win(&room, GameCharacter::X, kind, &line);
+        }
+    }
+
+    #[test]
+    fn needs_k_in_a_row_on_15x15() {
+        let four = [(7, 3), (8, 4), (9, 5), (10, 6)];
+        assert_eq!(
+            room(settings(15, 15, 5), GameCharacter::X, &four).check_winner(),
+            None
+        );
+
+        let five = [(7, 3), (8, 4), (9, 5), (10, 6), (11, 7)];
+        let room = room(settings(15, 15, 5), GameCharacter::X, &five);
+        assert_win(&room, GameCharacter::X, LineKind::Diagonal, &five);
+    }
+
+    #[test]
+    fn reports_the_whole_run_when_longer_than_k() {
+        let six = [(14, 9), (14, 10), (14, 11), (14, 12), (14, 13), (14, 14)];
+        let room = room(settings(15, 15, 5), GameCharacter::O, &six);
+        assert_win(&room, GameCharacter::O, LineKind::Row, &six);
+    }
+
+    #[test]
+    fn does_not_wrap_around_board_edges() {
+        // the end of row 0 and the start of row 1 are adjacent in memory, not on the board
+        let row_wrap = [(0, 3), (0, 4), (1, 0), (1, 1)];
+        assert_eq!(
+            room(settings(5, 5, 4), GameCharacter::X, &row_wrap).check_winner(),
+            None
+        );
+
+        let diagonal_wrap = [(2, 3), (3, 4), (4, 0)];
+        assert_eq!(
+            room(settings(5, 5, 3), GameCharacter::X, &diagonal_wrap).check_winner(),
+            None
+        );
+
+        let anti_diagonal_wrap = [(0, 1), (1, 0), (2, 4)];
+        assert_eq!(
+            room(settings(5, 5, 3), GameCharacter::X, &anti_diagonal_wrap).check_winner(),
+            None
+        );
+    }
+
+    #[test]
+    fn does_not_mix_characters() {
+        let mut room = room(settings(3, 3, 3), GameCharacter::X, &[(0, 0), (0, 1)]);
+        room.board[0][2] = Some(GameCharacter::O);
+        assert_eq!(room.check_winner(), None);
+    }
+
+    /// finished_game returns a room where `a` (X) beat `b` (O) on the top row.
+    fn finished_game(a: &String, b: &String) -> Room {
+        let mut room = Room::new(16, RoomSettings::default());
+        for user_id in [a, b] {
+            let player = Player {
+                user_id: user_id.clone(),
+                display_name: user_id.clone(),
+            };
+            room.join(player).unwrap();
+        }
+        room.start_game();
+        for (user_id, row, column) in [(a, 0, 0), (b, 1, 0), (a, 0, 1), (b, 1, 1), (a, 0, 2)] {
+            room.register_move(user_id, row, column).unwrap();
+        }
+        assert!(room.check_and_set_winner().is_some());
+        return room;
+    }
+
+    #[test]
+    fn accept_rematch_swaps_sides_and_starts_a_new_game() {
+        let (a, b) = ("a".to_string(), "b".to_string());
+        let mut room = finished_game(&a, &b);
+        room.request_rematch(&a).unwrap();
+
+        assert_eq!(room.accept_rematch(&b), Ok(()));
+        assert_eq!(room.get_character(&b), Some(GameCharacter::X));
+        assert_eq!(room.get_character(&a), Some(GameCharacter::O));
+        assert!(room.has_game_started() && !room.is_game_over());
+    }
+
+    #[test]
+    fn accept_rematch_fails_once_the_requester_left() {
+        let (a, b) = ("a".to_string(), "b".to_string());
+        let mut room = finished_game(&a, &b);
+        room.request_rematch(&a).unwrap();
+        room.leave(a.clone()).unwrap();
+
+        assert_eq!(room.accept_rematch(&b), Err(ServerError::NoRematchRequest));
+        assert_eq!(room.get_character(&b), Some(GameCharacter::O));
+        assert!(room.x.is_none());
+        assert!(room.is_game_over());
+    }
+
+    #[test]
+    fn running_clock_keeps_an_idle_game_open() {
+        let config = Config {
+            idle_room_ttl: Duration::from_secs(60),
+            ..Config::from_env()
+        };
+        let clock = ClockSettings::Fischer {
+            base_secs: 24 * 60 * 60,
+            increment_secs: 0,
+        };
+        for (clock, close_reason) in [(Some(clock), None), (None, Some(CloseReason::Idle))] {
+            let mut room = Room::new(
+                16,
+                RoomSettings {
+                    clock,
+                    ..RoomSettings::default()
+                },
+            );
+            for user_id in ["a", "b"] {
+                let player = Player {
+                    user_id: user_id.to_string(),
+                    display_name: user_id.to_string(),
+                };
+                room.join(player).unwrap();
+            }
+            room.start_game();
+            room.last_activity_at = Instant::now() - config.idle_room_ttl - Duration::from_secs(1);
+
+            assert_eq!(room.close_reason(&config), close_reason);
+        }
+    }
+
+    #[test]
+    fn validate_accepts_sizes_in_range() {
+        assert_eq!(settings(3, 3, 3).validate(), Ok(()));
+        assert_eq!(settings(20, 20, 20).validate(), Ok(()));
+        assert_eq!(settings(3, 20, 20).validate(), Ok(()));
+        assert_eq!(settings(15, 15, 5).validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_rejects_sizes_out_of_range() {
+        let invalid = [
+            settings(2, 3, 3),
+            settings(3, 2, 3),
+            settings(21, 3, 3),
+            settings(3, 21, 3),
+            settings(4, 5, 6),
+            settings(15, 15, 16),
+            settings(3, 3, 2),
+        ];
+        for settings in invalid {
+            assert_eq!(settings.validate(), Err(ServerError::InvalidSettings));
+        }
+    }
 }
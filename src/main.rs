@@ -1,21 +1,51 @@
-use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, pin::Pin, time::Instant};
 
 use axum::{
-    Router,
+    Extension, Router,
+    body::Bytes,
     extract::{State, WebSocketUpgrade, ws::Message},
     http::{StatusCode, Version},
+    middleware,
     response::IntoResponse,
-    routing::{any, get},
+    routing::{any, delete, get, post},
 };
 use axum_server::tls_rustls::RustlsConfig;
-use serde_json::json;
+use tokio::{
+    sync::{broadcast, mpsc},
+    time,
+};
+use tokio_stream::{
+    Stream, StreamExt, StreamMap,
+    wrappers::{BroadcastStream, errors::BroadcastStreamRecvError},
+};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod accounts;
+mod clock;
+mod config;
 mod game;
-use crate::game::{AppState, CommandType, Room, WebSocketMessage};
+mod metrics;
+mod protocol;
+mod reaper;
+mod rest;
+mod schema;
+mod session;
+use crate::config::Config;
+use crate::game::{
+    AppState, CommandType, CreateParams, DrawReason, FinishReason, HelloParams, MissedEvents,
+    MoveParams, Player, ResumeParams, Room, RoomUserParams, ServerError, ServerEvent,
+    ServerMessage, StateParams,
+};
+use crate::protocol::{ProtocolVersion, WireFormat};
+use crate::session::Claims;
 
 #[tokio::main]
 async fn main() {
+    if std::env::args().nth(1).as_deref() == Some("schema") {
+        println!("{:#}", schema::protocol_schema());
+        return;
+    }
+
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
@@ -31,10 +61,46 @@ async fn main() {
         .await
         .unwrap();
 
-    let app_state = AppState::new();
+    let app_state = AppState::new(Config::from_env());
+    tokio::spawn(reaper::reap_rooms(app_state.clone()));
+    let authenticated_routes = Router::new()
+        .route("/ws", any(ws_handler))
+        .route("/rooms", post(rest::create_room))
+        .route("/rooms/{room_id}", get(rest::get_room))
+        .route("/rooms/{room_id}/events", get(rest::room_events))
+        .route("/rooms/{room_id}/join", post(rest::join_room))
+        .route("/rooms/{room_id}/moves", post(rest::register_move))
+        .route("/rooms/{room_id}/resign", post(rest::resign))
+        .route("/rooms/{room_id}/draw/offer", post(rest::offer_draw))
+        .route("/rooms/{room_id}/draw/accept", post(rest::accept_draw))
+        .route("/rooms/{room_id}/draw/decline", post(rest::decline_draw))
+        .route("/rooms/{room_id}/undo/request", post(rest::request_undo))
+        .route("/rooms/{room_id}/undo/accept", post(rest::accept_undo))
+        .route("/rooms/{room_id}/undo/decline", post(rest::decline_undo))
+        .route(
+            "/rooms/{room_id}/rematch/request",
+            post(rest::request_rematch),
+        )
+        .route(
+            "/rooms/{room_id}/rematch/accept",
+            post(rest::accept_rematch),
+        )
+        .route(
+            "/rooms/{room_id}/players/{user_id}",
+            delete(rest::leave_room),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            session::require_session,
+        ));
     let app = Router::new()
         .route("/", get(index))
-        .route("/ws", any(ws_handler))
+        .route("/schema", get(schema::get_schema))
+        .route("/metrics", get(metrics::get_metrics))
+        .route("/sessions/guest", post(session::create_guest_session))
+        .route("/accounts/register", post(accounts::register))
+        .route("/accounts/login", post(accounts::login))
+        .merge(authenticated_routes)
         .with_state(app_state);
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
@@ -51,351 +117,978 @@ async fn index() -> axum::response::Response {
     return (StatusCode::OK, "Hello world").into_response();
 }
 
+/// RoomStream is the stream of the events of a room a connection is subscribed to.
+type RoomStream =
+    Pin<Box<dyn Stream<Item = Result<ServerMessage, BroadcastStreamRecvError>> + Send>>;
+
+/// Connection is the server-side view of a single WebSocket.
+/// Events for rooms the connection has created or joined arrive through `rooms`,
+/// while replies meant only for this connection (e.g. errors) go through `sender`.
+struct Connection {
+    /// sender is `None` for connections without a socket, e.g. HTTP requests.
+    sender: Option<mpsc::UnboundedSender<ServerMessage>>,
+    rooms: StreamMap<String, RoomStream>,
+    version: ProtocolVersion,
+    format: WireFormat,
+    /// is_version_locked is set once the version can no longer be changed with `hello`.
+    is_version_locked: bool,
+    /// user is the user of the session the connection was opened with.
+    /// Commands act on behalf of this user only.
+    user: Player,
+    /// request_ids are the `request_id`s of the room events caused by the commands of this connection,
+    /// by room and `seq`. They are put on this connection's copy of the event only.
+    request_ids: HashMap<(String, u64), String>,
+}
+
+impl Connection {
+    fn new(
+        sender: mpsc::UnboundedSender<ServerMessage>,
+        version: Option<ProtocolVersion>,
+        user: Player,
+    ) -> Connection {
+        return Connection {
+            sender: Some(sender),
+            rooms: StreamMap::new(),
+            version: version.unwrap_or(ProtocolVersion::DEFAULT),
+            format: WireFormat::Json,
+            is_version_locked: version.is_some(),
+            user,
+            request_ids: HashMap::new(),
+        };
+    }
+
+    /// detached returns a connection without a socket, acting on behalf of `user`.
+    /// Replies and room events sent to it are dropped.
+    fn detached(user: Player) -> Connection {
+        return Connection {
+            sender: None,
+            rooms: StreamMap::new(),
+            version: ProtocolVersion::LATEST,
+            format: WireFormat::Json,
+            is_version_locked: true,
+            user,
+            request_ids: HashMap::new(),
+        };
+    }
+
+    /// authenticate returns the user a command acts on behalf of.
+    /// A `claimed_user_id` sent with the command must match the user of the connection.
+    fn authenticate(&self, claimed_user_id: &Option<String>) -> Result<Player, ServerError> {
+        if let Some(claimed) = claimed_user_id
+            && *claimed != self.user.user_id
+        {
+            return Err(ServerError::IdentityMismatch);
+        }
+        return Ok(self.user.clone());
+    }
+
+    fn reply(&self, message: ServerMessage) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        if let Err(e) = sender.send(message) {
+            tracing::warn!("Reply failed: {e}");
+        }
+    }
+
+    /// broadcast sends the event caused by a command of this connection to the room.
+    /// Other subscribers receive it as is, while this connection's copy gets the `request_id`.
+    fn broadcast(
+        &mut self,
+        state: &AppState,
+        room_id: &String,
+        request_id: &Option<String>,
+        event: ServerEvent,
+    ) {
+        let seq = broadcast_to_room(state, room_id, event);
+        if let (Some(seq), Some(request_id)) = (seq, request_id)
+            && self.sender.is_some()
+            && self.rooms.contains_key(room_id)
+        {
+            self.request_ids
+                .insert((room_id.clone(), seq), request_id.clone());
+        }
+    }
+
+    /// tag puts the `request_id` on a room event caused by a command of this connection.
+    /// Request ids of earlier events are dropped, since those events have been delivered or skipped.
+    fn tag(&mut self, room_id: &String, message: &mut ServerMessage) {
+        let Some(seq) = message.seq else {
+            return;
+        };
+        if let Some(request_id) = self.request_ids.remove(&(room_id.clone(), seq)) {
+            message.request_id = Some(request_id);
+        }
+        self.request_ids
+            .retain(|(other_room_id, other_seq), _| other_room_id != room_id || *other_seq > seq);
+    }
+
+    fn subscribe(&mut self, state: &AppState, room_id: &String) {
+        let receiver_result =
+            get_room_and_execute_result(state, room_id, |room| Ok(room.subscribe()));
+        match receiver_result {
+            Ok(receiver) => self.watch(state, room_id, Box::pin(BroadcastStream::new(receiver))),
+            Err(e) => tracing::warn!("Subscribe to room {room_id} failed: {e}"),
+        }
+    }
+
+    /// watch delivers the events of `stream` to this connection, replacing any previous stream of the room.
+    /// A WebSocket watching a room for the first time counts as a connection of its user to the room.
+    fn watch(&mut self, state: &AppState, room_id: &String, stream: RoomStream) {
+        let is_new = self.rooms.insert(room_id.clone(), stream).is_none();
+        if is_new && self.sender.is_some() {
+            connect_to_room(state, room_id, &self.user.user_id);
+        }
+    }
+
+    fn unsubscribe(&mut self, state: &AppState, room_id: &String) {
+        self.request_ids
+            .retain(|(other_room_id, _), _| other_room_id != room_id);
+        if self.rooms.remove(room_id).is_some() && self.sender.is_some() {
+            disconnect_from_room(state, room_id, &self.user.user_id);
+        }
+    }
+
+    /// close disconnects the connection from every room it watches, once its socket is gone.
+    fn close(&mut self, state: &AppState) {
+        let room_ids: Vec<String> = self.rooms.keys().cloned().collect();
+        for room_id in room_ids {
+            self.unsubscribe(state, &room_id);
+        }
+    }
+}
+
+/// RoomPresence counts a Server-Sent Events stream as a connection of its user to the room,
+/// like a WebSocket, until the stream is dropped.
+struct RoomPresence {
+    state: AppState,
+    room_id: String,
+    user_id: String,
+}
+
+impl RoomPresence {
+    fn enter(state: AppState, room_id: String, user_id: String) -> RoomPresence {
+        connect_to_room(&state, &room_id, &user_id);
+        return RoomPresence {
+            state,
+            room_id,
+            user_id,
+        };
+    }
+}
+
+impl Drop for RoomPresence {
+    fn drop(&mut self) {
+        disconnect_from_room(&self.state, &self.room_id, &self.user_id);
+    }
+}
+
+/// connect_to_room counts a new connection of the user to the room,
+/// and tells the room when a disconnected player is back.
+fn connect_to_room(state: &AppState, room_id: &String, user_id: &String) {
+    let has_reconnected =
+        get_room_and_execute_option(state, room_id, |room| Some(room.connect(user_id)));
+    if has_reconnected == Some(true) {
+        let event = ServerEvent::PlayerReconnected {
+            room_id: room_id.clone(),
+            user_id: user_id.clone(),
+        };
+        broadcast_to_room(state, room_id, event);
+    }
+}
+
+/// disconnect_from_room tells the room when its player has no connection left,
+/// and starts the grace period after which their running game is forfeited.
+fn disconnect_from_room(state: &AppState, room_id: &String, user_id: &String) {
+    let Some(since) = get_room_and_execute_option(state, room_id, |room| room.disconnect(user_id))
+    else {
+        return;
+    };
+
+    let grace_period = state.config.forfeit_grace_period;
+    let event = ServerEvent::PlayerDisconnected {
+        room_id: room_id.clone(),
+        user_id: user_id.clone(),
+        grace_period_secs: grace_period.as_secs(),
+    };
+    broadcast_to_room(state, room_id, event);
+    tokio::spawn(forfeit_after_grace_period(
+        state.clone(),
+        room_id.clone(),
+        user_id.clone(),
+        since,
+    ));
+}
+
 async fn ws_handler(
     ws: WebSocketUpgrade,
     version: Version,
     State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
 ) -> axum::response::Response {
     tracing::debug!("Accepted a WebSocket using {version:?}");
-    let mut receiver = state.sender.subscribe();
-    return ws.on_upgrade(|mut socket| async move {
+    let ws = ws.protocols(ProtocolVersion::SUBPROTOCOLS);
+    let protocol_version = ws
+        .selected_protocol()
+        .and_then(|protocol| protocol.to_str().ok())
+        .and_then(ProtocolVersion::from_subprotocol);
+    return ws.on_upgrade(move |mut socket| async move {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<ServerMessage>();
+        let mut connection = Connection::new(sender, protocol_version, claims.player());
+        let ping_interval = state.config.ping_interval;
+        let mut heartbeat = time::interval_at(time::Instant::now() + ping_interval, ping_interval);
+        // last_seen is when the client last sent a frame, pongs included
+        let mut last_seen = Instant::now();
         loop {
             tokio::select! {
                 res = socket.recv() => {
+                    if let Some(Ok(_)) = res {
+                        last_seen = Instant::now();
+                    }
                     match res {
                         Some(Ok(Message::Text(message_text))) => {
-                            handle_socket_recv(&state, message_text.to_string());
+                            connection.format = WireFormat::Json;
+                            handle_socket_recv(&state, &mut connection, message_text.as_bytes());
+                        },
+                        Some(Ok(Message::Binary(payload))) => {
+                            connection.format = WireFormat::MessagePack;
+                            handle_socket_recv(&state, &mut connection, &payload);
                         },
                         Some(Ok(_)) => {},
                         Some(Err(e)) => tracing::warn!("Client disconnected abruptly: {e}"),
                         _ => break,
                     }
                 }
-                res = receiver.recv() => {
-                    match res {
-                        Ok(msg) => {
-                            if let Err(e) = socket.send(Message::from(msg)).await {
-                                tracing::warn!("Error on receiving message from state's sender: {e}");
+                Some(message) = receiver.recv() => {
+                    let Some(encoded) = encode_message(&connection, &message) else {
+                        continue
+                    };
+                    if let Err(e) = socket.send(encoded).await {
+                        tracing::warn!("Error on sending reply to client: {e}");
+                        continue
+                    }
+                }
+                Some((room_id, res)) = connection.rooms.next() => {
+                    let message = match res {
+                        Ok(mut message) => {
+                            connection.tag(&room_id, &mut message);
+                            message
+                        }
+                        Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                            let Some((message, receiver)) = catch_up_after_lag(&state, &room_id, skipped) else {
                                 continue
-                            }
-                        },
-                        _ => continue
+                            };
+                            connection.watch(&state, &room_id, Box::pin(BroadcastStream::new(receiver)));
+                            message
+                        }
+                    };
+                    let Some(encoded) = encode_message(&connection, &message) else {
+                        continue
+                    };
+                    if let Err(e) = socket.send(encoded).await {
+                        tracing::warn!("Error on receiving message from room {room_id}: {e}");
+                        continue
+                    }
+                }
+                _ = heartbeat.tick() => {
+                    if last_seen.elapsed() > state.config.idle_timeout {
+                        tracing::debug!(
+                            "Closing WebSocket of {}: no frame for {:?}",
+                            connection.user.user_id,
+                            last_seen.elapsed()
+                        );
+                        break;
+                    }
+                    if let Err(e) = socket.send(Message::Ping(Bytes::new())).await {
+                        tracing::warn!("Error on sending ping to client: {e}");
+                        break;
                     }
                 }
             }
         }
+        connection.close(&state);
     });
 }
 
-fn handle_socket_recv(state: &AppState, message_text: String) {
-    let ws_message_result = serde_json::from_str::<WebSocketMessage>(&message_text);
+fn encode_message(connection: &Connection, message: &ServerMessage) -> Option<Message> {
+    return connection
+        .version
+        .encode(message)
+        .map(|value| connection.format.encode(&value));
+}
+
+fn handle_socket_recv(state: &AppState, connection: &mut Connection, payload: &[u8]) {
+    let ws_message_result = connection.format.decode(payload).and_then(|value| {
+        return connection.version.decode(value).map_err(|e| e.to_string());
+    });
     if let Err(e) = ws_message_result {
-        tracing::warn!("Fail to parse message: {e}, original message: {message_text}");
+        tracing::warn!(
+            "Fail to parse message: {e}, original message: {}",
+            String::from_utf8_lossy(payload)
+        );
+        let event = ServerEvent::Error {
+            room_id: None,
+            user_id: None,
+            error: ServerError::InvalidMessage,
+            message: format!("{}: {e}", ServerError::InvalidMessage),
+        };
+        connection.reply(ServerMessage::new(event, None));
         return;
     }
     let ws_message = ws_message_result.unwrap();
-    let params = ws_message.params.unwrap();
-    match ws_message.command {
-        CommandType::Create => {
-            create_room(state, params);
-        }
-        CommandType::Join => {
-            join_room(state, params);
-        }
-        CommandType::Leave => {
-            leave_room(state, params);
+    let command = ws_message.command;
+    let request_id = ws_message.request_id;
+    let result = execute_command(state, connection, &request_id, &command);
+    if !matches!(command, CommandType::Hello { .. }) {
+        connection.is_version_locked = true;
+    }
+    if let Err(e) = result {
+        let user_id = command.user_id().or(Some(connection.user.user_id.clone()));
+        let event = ServerEvent::error(command.room_id(), user_id, e);
+        connection.reply(ServerMessage::new(event, request_id.clone()));
+    }
+
+    if request_id.is_none() {
+        return;
+    }
+    let acknowledgement = match result {
+        Ok(_) => ServerEvent::Ack {
+            command: command.name().to_string(),
+        },
+        Err(e) => ServerEvent::Nack {
+            error: e,
+            message: e.message().to_string(),
+        },
+    };
+    connection.reply(ServerMessage::new(acknowledgement, request_id));
+}
+
+/// execute_command validates the command and runs its handler.
+/// On success it returns the main event caused by the command.
+fn execute_command(
+    state: &AppState,
+    connection: &mut Connection,
+    request_id: &Option<String>,
+    command: &CommandType,
+) -> Result<ServerEvent, ServerError> {
+    command.validate()?;
+    return match command {
+        CommandType::Hello { params } => say_hello(connection, request_id, params),
+        CommandType::Create { params } => create_room(state, connection, request_id, params),
+        CommandType::Join { params } => join_room(state, connection, request_id, params),
+        CommandType::Leave { params } => leave_room(state, connection, request_id, params),
+        CommandType::Move { params } => register_move(state, connection, request_id, params),
+        CommandType::State { params } => get_room_state(state, connection, request_id, params),
+        CommandType::Resume { params } => resume_room(state, connection, request_id, params),
+        CommandType::Resign { params } => resign(state, connection, request_id, params),
+        CommandType::OfferDraw { params } => offer_draw(state, connection, request_id, params),
+        CommandType::AcceptDraw { params } => accept_draw(state, connection, request_id, params),
+        CommandType::DeclineDraw { params } => decline_draw(state, connection, request_id, params),
+        CommandType::RequestUndo { params } => request_undo(state, connection, request_id, params),
+        CommandType::AcceptUndo { params } => accept_undo(state, connection, request_id, params),
+        CommandType::DeclineUndo { params } => decline_undo(state, connection, request_id, params),
+        CommandType::RequestRematch { params } => {
+            request_rematch(state, connection, request_id, params)
         }
-        CommandType::Move => {
-            register_move(state, params);
+        CommandType::AcceptRematch { params } => {
+            accept_rematch(state, connection, request_id, params)
         }
+    };
+}
+
+/// say_hello switches the connection to the requested protocol version.
+/// It must be the first message sent by a client that did not negotiate a WebSocket subprotocol.
+fn say_hello(
+    connection: &mut Connection,
+    request_id: &Option<String>,
+    params: &HelloParams,
+) -> Result<ServerEvent, ServerError> {
+    if connection.is_version_locked {
+        return Err(ServerError::VersionAlreadyNegotiated);
     }
+
+    let version =
+        ProtocolVersion::from_number(params.version).ok_or(ServerError::UnsupportedVersion)?;
+    connection.version = version;
+    connection.is_version_locked = true;
+    let event = ServerEvent::Welcome {
+        version: version.number(),
+    };
+    connection.reply(ServerMessage::new(event.clone(), request_id.clone()));
+    return Ok(event);
 }
 
-fn create_room(state: &AppState, params: HashMap<String, String>) {
-    let user_id = params.get("user_id").unwrap().to_string();
+fn create_room(
+    state: &AppState,
+    connection: &mut Connection,
+    request_id: &Option<String>,
+    params: &CreateParams,
+) -> Result<ServerEvent, ServerError> {
+    let user_id = connection.authenticate(&params.user_id)?.user_id;
+    params.settings.validate()?;
     let room_id = uuid::Uuid::now_v7().to_string();
 
     match state.rooms.lock() {
         Ok(mut rooms) => {
-            rooms.insert(room_id.clone(), Room::new());
+            rooms.insert(
+                room_id.clone(),
+                Room::new(state.config.room_channel_capacity, params.settings.clone()),
+            );
         }
         Err(e) => {
             tracing::error!("Fail to lock room: {e}");
-            return;
+            return Err(ServerError::InternalError);
         }
     };
+    connection.subscribe(state, &room_id);
 
-    let message = json!({
-        "room_id": room_id,
-        "user_id": &user_id,
-        "event": "ROOM_CREATED"
-    });
-    let send_result = state.sender.send(message.to_string());
-    if let Err(e) = send_result {
-        tracing::warn!("Send message failed: {e}");
-        return;
-    }
+    let event = ServerEvent::RoomCreated {
+        room_id: room_id.clone(),
+        user_id,
+        settings: params.settings.clone(),
+    };
+    connection.broadcast(state, &room_id, request_id, event.clone());
+    return Ok(event);
 }
 
-fn join_room(state: &AppState, params: HashMap<String, String>) {
-    let room_id = params.get("room_id").unwrap().to_string();
-    let user_id = params.get("user_id").unwrap().to_string();
+fn join_room(
+    state: &AppState,
+    connection: &mut Connection,
+    request_id: &Option<String>,
+    params: &RoomUserParams,
+) -> Result<ServerEvent, ServerError> {
+    let room_id = params.room_id.clone();
+    let player = connection.authenticate(&params.user_id)?;
 
-    {
-        let rooms = state.rooms.lock().unwrap();
-        let room_exists = rooms.contains_key(&room_id);
-        if !room_exists {
-            let message = json!({
-                "room_id": &room_id,
-                "error": "Room does not exist!",
-                "user_id": &user_id
-            });
-            state.sender.send(message.to_string()).unwrap();
-            return;
-        }
+    if !room_exists(state, &room_id) {
+        return Err(ServerError::RoomNotFound);
     }
 
-    let is_game_started = has_game_started(state, &room_id);
-    if is_game_started {
-        let message = json!({
-            "room_id": &room_id,
-            "error": "Game has already started!",
-            "user_id": &user_id
-        });
-        state.sender.send(message.to_string()).unwrap();
-        return;
+    if has_game_started(state, &room_id) {
+        return Err(ServerError::GameAlreadyStarted);
     }
 
-    let is_full = is_room_full(&state, &room_id);
-    if is_full {
-        let message = json!({
-            "room_id": &room_id,
-            "user_id": user_id,
-            "error": "Room is already full"
-        });
-        state.sender.send(message.to_string()).unwrap();
-        return;
+    if is_room_full(state, &room_id) {
+        return Err(ServerError::RoomFull);
     }
 
-    let character_result =
-        get_room_and_execute_result(state, &room_id, |room| room.join(user_id.clone()));
-    let send_result = match character_result {
-        Ok(character) => {
-            let message = json!({
-                "room_id": &room_id,
-                "user_id": user_id,
-                "event": "ROOM_JOINED",
-                "character": character,
-            });
-            state.sender.send(message.to_string())
-        }
-        Err(e) => {
-            let message = json!({
-                "room_id": &room_id,
-                "user_id": user_id,
-                "error": e,
-            });
-            state.sender.send(message.to_string())
-        }
+    let character = get_room_and_execute_result(state, &room_id, |room| room.join(player.clone()))?;
+    connection.subscribe(state, &room_id);
+    let event = ServerEvent::RoomJoined {
+        room_id: room_id.clone(),
+        user_id: player.user_id,
+        display_name: player.display_name,
+        character,
     };
-    if let Err(e) = send_result {
-        tracing::warn!("Send message failed: {e}");
-        return;
-    }
+    connection.broadcast(state, &room_id, request_id, event.clone());
 
-    if is_room_full(&state, &room_id) {
-        let message = json!({
-            "room_id": &room_id,
-            "event": "GAME_STARTED"
-        });
-        state.sender.send(message.to_string()).unwrap();
+    if is_room_full(state, &room_id) {
+        connection.broadcast(
+            state,
+            &room_id,
+            request_id,
+            ServerEvent::GameStarted {
+                room_id: room_id.clone(),
+            },
+        );
+        watch_clock(state, &room_id);
     }
+    return Ok(event);
 }
 
-fn leave_room(state: &AppState, params: HashMap<String, String>) {
-    let room_id = params.get("room_id").unwrap().to_string();
-    let user_id = params.get("user_id").unwrap().to_string();
+fn leave_room(
+    state: &AppState,
+    connection: &mut Connection,
+    request_id: &Option<String>,
+    params: &RoomUserParams,
+) -> Result<ServerEvent, ServerError> {
+    let room_id = params.room_id.clone();
+    let user_id = connection.authenticate(&params.user_id)?.user_id;
 
-    {
-        let rooms = state.rooms.lock().unwrap();
-        let room_exists = rooms.contains_key(&room_id);
-        if !room_exists {
-            let message = json!({
-                "room_id": &room_id,
-                "error": "Room does not exist!",
-                "user_id": &user_id
-            });
-            state.sender.send(message.to_string()).unwrap();
-            return;
-        }
+    if !room_exists(state, &room_id) {
+        return Err(ServerError::RoomNotFound);
     }
 
-    let is_game_started = has_game_started(state, &room_id);
-    if is_game_started {
-        let message = json!({
-            "room_id": &room_id,
-            "error": "Game has already started!",
-            "user_id": &user_id
-        });
-        state.sender.send(message.to_string()).unwrap();
-        return;
+    // leaving a running game resigns it
+    let is_game_running = get_room_and_execute_option(state, &room_id, |room| {
+        Some(room.has_game_started() && !room.is_game_over())
+    });
+    let mut resignation = None;
+    if is_game_running == Some(true) {
+        resignation = Some(resign_game(
+            state, connection, &room_id, request_id, &user_id,
+        )?);
     }
 
-    let leave_result =
-        get_room_and_execute_result(state, &room_id, |room| room.leave(user_id.clone()));
-    let send_result = match leave_result {
-        Ok(prev_char) => {
-            let message = json!({
-                "room_id": &room_id,
-                "user_id": &user_id,
-                "event": "ROOM_LEFT",
-                "character": prev_char,
-            });
-            state.sender.send(message.to_string())
-        }
-        Err(e) => {
-            let message = json!({
-                "room_id": &room_id,
-                "user_id": user_id,
-                "error": e,
-            });
-            state.sender.send(message.to_string())
-        }
-    };
-    if let Err(e) = send_result {
-        tracing::warn!("Send message failed: {e}");
+    let prev_char =
+        get_room_and_execute_result(state, &room_id, |room| room.leave(user_id.clone()))?;
+
+    // the leaving connection no longer receives room events, so it gets its own copy
+    connection.unsubscribe(state, &room_id);
+    if let Some(resignation) = resignation {
+        connection.reply(ServerMessage::new(resignation, request_id.clone()));
     }
+    let event = ServerEvent::RoomLeft {
+        room_id: room_id.clone(),
+        user_id,
+        character: prev_char,
+    };
+    connection.reply(ServerMessage::new(event.clone(), request_id.clone()));
+    broadcast_to_room(state, &room_id, event.clone());
 
-    let is_room_empty =
-        get_room_and_execute_result(state, &room_id, |room| Ok(room.is_empty())).unwrap();
+    let is_room_empty = get_room_and_execute_result(state, &room_id, |room| Ok(room.is_empty()))?;
     if is_room_empty {
         let mut rooms = state.rooms.lock().unwrap();
         rooms.remove(&room_id);
     }
+    return Ok(event);
 }
 
-fn register_move(state: &AppState, params: HashMap<String, String>) {
-    let room_id = params.get("room_id").unwrap().to_string();
-    let user_id = params.get("user_id").unwrap().to_string();
+fn resign(
+    state: &AppState,
+    connection: &mut Connection,
+    request_id: &Option<String>,
+    params: &RoomUserParams,
+) -> Result<ServerEvent, ServerError> {
+    let room_id = params.room_id.clone();
+    let user_id = connection.authenticate(&params.user_id)?.user_id;
+    return resign_game(state, connection, &room_id, request_id, &user_id);
+}
 
-    {
-        let rooms = state.rooms.lock().unwrap();
-        let room_exists = rooms.contains_key(&room_id);
-        if !room_exists {
-            let message = json!({
-                "room_id": &room_id,
-                "error": "Room does not exist!",
-                "user_id": &user_id
+/// resign_game ends the running game in favor of the opponent of the user.
+fn resign_game(
+    state: &AppState,
+    connection: &mut Connection,
+    room_id: &String,
+    request_id: &Option<String>,
+    user_id: &String,
+) -> Result<ServerEvent, ServerError> {
+    let (winner_character, winner) =
+        get_room_and_execute_result(state, room_id, |room| room.resign(user_id))?;
+    let event = ServerEvent::GameFinished {
+        room_id: room_id.clone(),
+        user_id: user_id.clone(),
+        winner_user_id: winner.user_id,
+        winner_display_name: winner.display_name,
+        winner_character,
+        reason: FinishReason::Resignation,
+        winning_line: None,
+    };
+    connection.broadcast(state, room_id, request_id, event.clone());
+    return Ok(event);
+}
+
+fn offer_draw(
+    state: &AppState,
+    connection: &mut Connection,
+    request_id: &Option<String>,
+    params: &RoomUserParams,
+) -> Result<ServerEvent, ServerError> {
+    let room_id = params.room_id.clone();
+    let user_id = connection.authenticate(&params.user_id)?.user_id;
+    get_room_and_execute_result(state, &room_id, |room| room.offer_draw(&user_id))?;
+
+    let event = ServerEvent::DrawOffered {
+        room_id: room_id.clone(),
+        user_id,
+    };
+    connection.broadcast(state, &room_id, request_id, event.clone());
+    return Ok(event);
+}
+
+fn accept_draw(
+    state: &AppState,
+    connection: &mut Connection,
+    request_id: &Option<String>,
+    params: &RoomUserParams,
+) -> Result<ServerEvent, ServerError> {
+    let room_id = params.room_id.clone();
+    let user_id = connection.authenticate(&params.user_id)?.user_id;
+    get_room_and_execute_result(state, &room_id, |room| room.accept_draw(&user_id))?;
+
+    let event = ServerEvent::GameDrawn {
+        room_id: room_id.clone(),
+        reason: DrawReason::Agreement,
+    };
+    connection.broadcast(state, &room_id, request_id, event.clone());
+    return Ok(event);
+}
+
+fn decline_draw(
+    state: &AppState,
+    connection: &mut Connection,
+    request_id: &Option<String>,
+    params: &RoomUserParams,
+) -> Result<ServerEvent, ServerError> {
+    let room_id = params.room_id.clone();
+    let user_id = connection.authenticate(&params.user_id)?.user_id;
+    get_room_and_execute_result(state, &room_id, |room| room.decline_draw(&user_id))?;
+
+    let event = ServerEvent::DrawDeclined {
+        room_id: room_id.clone(),
+        user_id,
+    };
+    connection.broadcast(state, &room_id, request_id, event.clone());
+    return Ok(event);
+}
+
+fn request_undo(
+    state: &AppState,
+    connection: &mut Connection,
+    request_id: &Option<String>,
+    params: &RoomUserParams,
+) -> Result<ServerEvent, ServerError> {
+    let room_id = params.room_id.clone();
+    let user_id = connection.authenticate(&params.user_id)?.user_id;
+    get_room_and_execute_result(state, &room_id, |room| room.request_undo(&user_id))?;
+
+    let event = ServerEvent::UndoRequested {
+        room_id: room_id.clone(),
+        user_id,
+    };
+    connection.broadcast(state, &room_id, request_id, event.clone());
+    return Ok(event);
+}
+
+fn accept_undo(
+    state: &AppState,
+    connection: &mut Connection,
+    request_id: &Option<String>,
+    params: &RoomUserParams,
+) -> Result<ServerEvent, ServerError> {
+    let room_id = params.room_id.clone();
+    let user_id = connection.authenticate(&params.user_id)?.user_id;
+    let (undone, board) =
+        get_room_and_execute_result(state, &room_id, |room| room.accept_undo(&user_id))?;
+
+    let event = ServerEvent::MoveUndone {
+        room_id: room_id.clone(),
+        user_id,
+        row: undone.row,
+        column: undone.column,
+        board_after_undo: board,
+        current_turn: undone.character,
+    };
+    connection.broadcast(state, &room_id, request_id, event.clone());
+    watch_clock(state, &room_id);
+    return Ok(event);
+}
+
+fn decline_undo(
+    state: &AppState,
+    connection: &mut Connection,
+    request_id: &Option<String>,
+    params: &RoomUserParams,
+) -> Result<ServerEvent, ServerError> {
+    let room_id = params.room_id.clone();
+    let user_id = connection.authenticate(&params.user_id)?.user_id;
+    get_room_and_execute_result(state, &room_id, |room| room.decline_undo(&user_id))?;
+
+    let event = ServerEvent::UndoDeclined {
+        room_id: room_id.clone(),
+        user_id,
+    };
+    connection.broadcast(state, &room_id, request_id, event.clone());
+    return Ok(event);
+}
+
+fn request_rematch(
+    state: &AppState,
+    connection: &mut Connection,
+    request_id: &Option<String>,
+    params: &RoomUserParams,
+) -> Result<ServerEvent, ServerError> {
+    let room_id = params.room_id.clone();
+    let user_id = connection.authenticate(&params.user_id)?.user_id;
+    get_room_and_execute_result(state, &room_id, |room| room.request_rematch(&user_id))?;
+
+    let event = ServerEvent::RematchRequested {
+        room_id: room_id.clone(),
+        user_id,
+    };
+    connection.broadcast(state, &room_id, request_id, event.clone());
+    return Ok(event);
+}
+
+fn accept_rematch(
+    state: &AppState,
+    connection: &mut Connection,
+    request_id: &Option<String>,
+    params: &RoomUserParams,
+) -> Result<ServerEvent, ServerError> {
+    let room_id = params.room_id.clone();
+    let user_id = connection.authenticate(&params.user_id)?.user_id;
+    let snapshot = get_room_and_execute_result(state, &room_id, |room| {
+        room.accept_rematch(&user_id)?;
+        return Ok(room.snapshot(room_id.clone()));
+    })?;
+
+    let (Some(x), Some(o)) = (snapshot.x, snapshot.o) else {
+        return Err(ServerError::InternalError);
+    };
+    let event = ServerEvent::RematchStarted {
+        room_id: room_id.clone(),
+        x,
+        o,
+        score: snapshot.score,
+    };
+    connection.broadcast(state, &room_id, request_id, event.clone());
+    watch_clock(state, &room_id);
+    return Ok(event);
+}
+
+fn register_move(
+    state: &AppState,
+    connection: &mut Connection,
+    request_id: &Option<String>,
+    params: &MoveParams,
+) -> Result<ServerEvent, ServerError> {
+    let room_id = params.room_id.clone();
+    let user_id = connection.authenticate(&params.user_id)?.user_id;
+
+    // a move made too late loses the game, even when its timer has not fired yet
+    if time_out(state, &room_id) {
+        return Err(ServerError::GameAlreadyFinished);
+    }
+
+    // the move and its outcome are decided under one lock, so no other command can slip in between
+    let (board, clocks, winner_option, is_draw) =
+        get_room_and_execute_result(state, &room_id, |room| {
+            let board = room.register_move(&user_id, params.row, params.column)?;
+            let winner_option = room.check_and_set_winner().and_then(|(character, line)| {
+                return room
+                    .get_player_from_character(character)
+                    .map(|winner| (character, winner, line));
             });
-            state.sender.send(message.to_string()).unwrap();
-            return;
-        }
+            return Ok((
+                board,
+                room.clock_times(),
+                winner_option,
+                room.is_game_draw(),
+            ));
+        })?;
+    let event = ServerEvent::MoveRegistered {
+        room_id: room_id.clone(),
+        user_id: user_id.clone(),
+        board_after_move: board,
+        clocks,
+    };
+    connection.broadcast(state, &room_id, request_id, event.clone());
+
+    if let Some((winner_character, winner, line)) = winner_option {
+        connection.broadcast(
+            state,
+            &room_id,
+            request_id,
+            ServerEvent::GameFinished {
+                room_id: room_id.clone(),
+                user_id,
+                winner_user_id: winner.user_id,
+                winner_display_name: winner.display_name,
+                winner_character,
+                reason: FinishReason::Line,
+                winning_line: Some(line),
+            },
+        );
+        return Ok(event);
     }
 
-    let has_game_finished = has_game_finished(state, &room_id);
-    if has_game_finished {
-        let message = json!({
-            "room_id": &room_id,
-            "error": "Game has already finished!",
-            "user_id": &user_id
-        });
-        state.sender.send(message.to_string()).unwrap();
-        return;
+    if is_draw {
+        connection.broadcast(
+            state,
+            &room_id,
+            request_id,
+            ServerEvent::GameDrawn {
+                room_id: room_id.clone(),
+                reason: DrawReason::BoardFull,
+            },
+        );
+        return Ok(event);
     }
 
-    let row = params
-        .get("row")
-        .unwrap()
-        .to_string()
-        .parse::<usize>()
-        .unwrap();
-    let column = params
-        .get("column")
-        .unwrap()
-        .to_string()
-        .parse::<usize>()
-        .unwrap();
+    watch_clock(state, &room_id);
+    return Ok(event);
+}
 
-    let character = get_room_and_execute_result(state, &room_id, |room| {
-        let result = room.get_character(&user_id);
-        return match result {
-            Some(value) => Ok(value),
-            None => Err(String::from("User not found")),
-        };
-    })
-    .unwrap();
-
-    let expected_character_option =
-        get_room_and_execute_option(state, &room_id, |room| room.get_current_turn());
-    if let Some(expected_character) = expected_character_option {
-        if character != expected_character {
-            let message = json!({
-                "room_id": &room_id,
-                "user_id": user_id,
-                "error": "Invalid character",
-            });
-            state.sender.send(message.to_string()).unwrap();
-            return;
-        }
-    }
+/// get_room_state replies the current state of the room to the caller only.
+fn get_room_state(
+    state: &AppState,
+    connection: &mut Connection,
+    request_id: &Option<String>,
+    params: &StateParams,
+) -> Result<ServerEvent, ServerError> {
+    let snapshot = get_room_and_execute_result(state, &params.room_id, |room| {
+        return Ok(room.snapshot(params.room_id.clone()));
+    })?;
+
+    let event = ServerEvent::RoomState(snapshot);
+    connection.reply(ServerMessage::new(event.clone(), request_id.clone()));
+    return Ok(event);
+}
+
+/// resume_room subscribes the connection to the room again, e.g. after reconnecting.
+/// `RESUMED` is followed by the events published after `last_seq`, or by a `ROOM_STATE`
+/// when they are no longer kept, and then by the live events of the room.
+fn resume_room(
+    state: &AppState,
+    connection: &mut Connection,
+    request_id: &Option<String>,
+    params: &ResumeParams,
+) -> Result<ServerEvent, ServerError> {
+    let room_id = params.room_id.clone();
+    let user_id = connection.user.user_id.clone();
+    let (missed_events, receiver) = subscribe_after(state, &room_id, &user_id, params.last_seq)?;
+    let last_seq = match &missed_events {
+        MissedEvents::Replay(events) => events
+            .last()
+            .and_then(|message| message.seq)
+            .unwrap_or(params.last_seq),
+        MissedEvents::Snapshot(snapshot) => snapshot.last_seq,
+    };
+    let event = ServerEvent::Resumed {
+        room_id: room_id.clone(),
+        last_seq,
+        is_snapshot: matches!(missed_events, MissedEvents::Snapshot(_)),
+    };
 
-    let register_move_result = get_room_and_execute_result(state, &room_id, |room| {
-        room.register_move(row, column, character)
+    // the missed events go through the room stream, so they cannot overtake the live events
+    let mut messages = vec![ServerMessage::new(event.clone(), request_id.clone())];
+    messages.extend(missed_events.into_messages(request_id));
+    let stream =
+        tokio_stream::iter(messages.into_iter().map(Ok)).chain(BroadcastStream::new(receiver));
+    connection.watch(state, &room_id, Box::pin(stream));
+    return Ok(event);
+}
+
+/// forfeit_after_grace_period ends the running game of the room in favor of the opponent,
+/// unless the player reconnects within the grace period.
+async fn forfeit_after_grace_period(
+    state: AppState,
+    room_id: String,
+    user_id: String,
+    since: Instant,
+) {
+    tokio::time::sleep(state.config.forfeit_grace_period).await;
+    let winner_option =
+        get_room_and_execute_option(&state, &room_id, |room| room.forfeit(&user_id, since));
+    let Some((winner_character, winner)) = winner_option else {
+        return;
+    };
+
+    tracing::debug!("{user_id} forfeited the game in room {room_id}");
+    let event = ServerEvent::GameFinished {
+        room_id: room_id.clone(),
+        user_id,
+        winner_user_id: winner.user_id,
+        winner_display_name: winner.display_name,
+        winner_character,
+        reason: FinishReason::Forfeit,
+        winning_line: None,
+    };
+    broadcast_to_room(&state, &room_id, event);
+}
+
+/// watch_clock ends the game when the player to move runs out of time.
+/// A move postpones the deadline, so the timer of an earlier deadline finds nobody out of time.
+fn watch_clock(state: &AppState, room_id: &String) {
+    let Some(deadline) = get_room_and_execute_option(state, room_id, |room| room.clock_deadline())
+    else {
+        return;
+    };
+    let state = state.clone();
+    let room_id = room_id.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep_until(deadline.into()).await;
+        time_out(&state, &room_id);
     });
-    let send_result = match register_move_result {
-        Ok(board) => {
-            let message = json!({
-                "room_id": &room_id,
-                "user_id": &user_id,
-                "event": "MOVE_REGISTERED",
-                "board_after_move": board
-            });
-            state.sender.send(message.to_string())
-        }
+}
+
+/// time_out ends the game in favor of the opponent of the player to move, if they ran out of time.
+/// It tells whether the game ended.
+fn time_out(state: &AppState, room_id: &String) -> bool {
+    let Some((user_id, winner_character, winner)) =
+        get_room_and_execute_option(state, room_id, |room| room.time_out())
+    else {
+        return false;
+    };
+
+    tracing::debug!("{user_id} ran out of time in room {room_id}");
+    let event = ServerEvent::GameFinished {
+        room_id: room_id.clone(),
+        user_id,
+        winner_user_id: winner.user_id,
+        winner_display_name: winner.display_name,
+        winner_character,
+        reason: FinishReason::Timeout,
+        winning_line: None,
+    };
+    broadcast_to_room(state, room_id, event);
+    return true;
+}
+
+/// catch_up_after_lag returns a `ROOM_STATE` for a subscriber that fell behind the room channel,
+/// since the events it skipped are gone and its view of the room can no longer be trusted.
+/// It also returns a fresh receiver, subscribed under the same lock as the snapshot,
+/// so the subscriber can drop the stale events still buffered in its old one.
+fn catch_up_after_lag(
+    state: &AppState,
+    room_id: &String,
+    skipped: u64,
+) -> Option<(ServerMessage, broadcast::Receiver<ServerMessage>)> {
+    tracing::warn!("A subscriber of room {room_id} lagged behind and skipped {skipped} events");
+    state.metrics.record_lag(skipped);
+    let snapshot_result = get_room_and_execute_result(state, room_id, |room| {
+        return Ok((room.snapshot(room_id.clone()), room.subscribe()));
+    });
+    return match snapshot_result {
+        Ok((snapshot, receiver)) => Some((
+            ServerMessage::new(ServerEvent::RoomState(snapshot), None),
+            receiver,
+        )),
         Err(e) => {
-            let message = json!({
-                "room_id": &room_id,
-                "user_id": user_id,
-                "error": e,
-            });
-            state.sender.send(message.to_string())
+            tracing::warn!("Catching up with room {room_id} failed: {e}");
+            None
         }
     };
-    if let Err(e) = send_result {
-        tracing::warn!("Send message failed: {e}");
-        return;
-    }
+}
 
-    let winner_user_option = get_room_and_execute_option(state, &room_id, |room| {
-        let w = room.check_and_set_winner();
-        match w {
-            Some(character) => room.get_user_id_from_character(character),
-            _ => None,
-        }
+/// subscribe_after subscribes a player of the room to it and returns the events published after `last_seq`.
+/// Both happen under the same lock, so no event is missed or repeated in between.
+fn subscribe_after(
+    state: &AppState,
+    room_id: &String,
+    user_id: &String,
+    last_seq: u64,
+) -> Result<(MissedEvents, broadcast::Receiver<ServerMessage>), ServerError> {
+    return get_room_and_execute_result(state, room_id, |room| {
+        room.get_character(user_id)
+            .ok_or(ServerError::UserNotInRoom)?;
+        return Ok((
+            room.missed_events(room_id.clone(), last_seq),
+            room.subscribe(),
+        ));
     });
-    if let Some(winner_user) = winner_user_option {
-        let message = json!({
-            "room_id": &room_id,
-            "user_id": &user_id,
-            "event": "GAME_FINISHED",
-            "winner_user_id": winner_user.1,
-            "winner_character": winner_user.0,
-        });
-        state.sender.send(message.to_string()).unwrap();
-        return;
-    }
+}
 
-    let is_draw_option =
-        get_room_and_execute_option(state, &room_id, |room| Some(room.is_game_draw()));
-    if let Some(is_draw) = is_draw_option {
-        if is_draw {
-            let message = json!({
-                "room_id": &room_id,
-                "event": "GAME_DRAWN",
-            });
-            state.sender.send(message.to_string()).unwrap();
+/// broadcast_to_room sends the event to every connection subscribed to the room.
+/// It returns the sequence number of the event.
+/// The event is shared by every subscriber, so it never carries a `request_id`, see `Connection::broadcast`.
+fn broadcast_to_room(state: &AppState, room_id: &String, event: ServerEvent) -> Option<u64> {
+    let message = ServerMessage::new(event, None);
+    let send_result =
+        get_room_and_execute_result(state, room_id, |room| Ok(room.broadcast(message)));
+    return match send_result {
+        Ok(seq) => Some(seq),
+        Err(e) => {
+            tracing::warn!("Send message failed: {e}");
+            None
         }
-    }
+    };
+}
+
+fn room_exists(state: &AppState, room_id: &String) -> bool {
+    let rooms = state.rooms.lock().unwrap();
+    return rooms.contains_key(room_id);
 }
 
 fn is_room_full(state: &AppState, room_id: &String) -> bool {
@@ -406,41 +1099,34 @@ fn is_room_full(state: &AppState, room_id: &String) -> bool {
 
         return Ok(room.is_full());
     });
-    return match result {
-        Ok(b) => b,
-        Err(_) => false,
-    };
+    return result.unwrap_or_default();
 }
 
 fn has_game_started(state: &AppState, room_id: &String) -> bool {
     let result = get_room_and_execute_result(state, room_id, |room| Ok(room.has_game_started()));
-    return match result {
-        Ok(b) => b,
-        Err(_) => false,
-    };
-}
-
-fn has_game_finished(state: &AppState, room_id: &String) -> bool {
-    let result = get_room_and_execute_result(state, room_id, |room| Ok(room.has_game_finished()));
-    return match result {
-        Ok(b) => b,
-        Err(_) => false,
-    };
+    return result.unwrap_or_default();
 }
 
 /// references:
 /// - https://www.reddit.com/r/learnrust/comments/xvxpy2/is_there_a_workaround_for_variable_capturing_in/
 /// - https://doc.rust-lang.org/book/ch13-01-closures.html
-fn get_room_and_execute_result<T, F>(state: &AppState, room_id: &String, f: F) -> Result<T, String>
+fn get_room_and_execute_result<T, F>(
+    state: &AppState,
+    room_id: &String,
+    f: F,
+) -> Result<T, ServerError>
 where
-    F: FnOnce(&mut Room) -> Result<T, String>,
+    F: FnOnce(&mut Room) -> Result<T, ServerError>,
 {
     return match state.rooms.lock() {
         Ok(mut rooms) => match rooms.get_mut(room_id) {
             Some(room) => f(room),
-            None => Err(String::from("Room not found")),
+            None => Err(ServerError::RoomNotFound),
         },
-        Err(e) => Err(format!("Fail to lock room: {}", e).to_string()),
+        Err(e) => {
+            tracing::error!("Fail to lock room: {e}");
+            Err(ServerError::InternalError)
+        }
     };
 }
 
@@ -0,0 +1,52 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use axum::{extract::State, http::header, response::IntoResponse};
+
+use crate::game::AppState;
+
+/// Metrics are the counters served by `GET /metrics`, in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    /// lagged_subscribers counts the times a room subscriber fell behind the room channel.
+    lagged_subscribers: AtomicU64,
+    /// skipped_events counts the events lagging subscribers lost, and caught up on with a snapshot.
+    skipped_events: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_lag(&self, skipped_events: u64) {
+        self.lagged_subscribers.fetch_add(1, Ordering::Relaxed);
+        self.skipped_events
+            .fetch_add(skipped_events, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let counters = [
+            (
+                "tictactoe_room_lagged_subscribers_total",
+                "Times a room subscriber fell behind the room channel.",
+                &self.lagged_subscribers,
+            ),
+            (
+                "tictactoe_room_skipped_events_total",
+                "Room events skipped by lagging subscribers.",
+                &self.skipped_events,
+            ),
+        ];
+
+        let mut text = String::new();
+        for (name, help, counter) in counters {
+            text.push_str(&format!("# HELP {name} {help}\n"));
+            text.push_str(&format!("# TYPE {name} counter\n"));
+            text.push_str(&format!("{name} {}\n", counter.load(Ordering::Relaxed)));
+        }
+        return text;
+    }
+}
+
+pub async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    return (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    );
+}
@@ -0,0 +1,277 @@
+use axum::extract::ws::Message;
+use serde_json::Value;
+
+use crate::game::{ServerError, ServerEvent, ServerMessage, WebSocketMessage};
+
+/// ProtocolVersion is the shape of the JSON messages exchanged with a client.
+///
+/// - V1 is the original protocol: `params` values are strings (e.g. `"row": "0"`),
+///   errors are free text in the `error` field and commands are not acknowledged.
+/// - V2 is the typed protocol: numeric coordinates, error codes, `ACK`/`NACK`.
+///
+/// Clients that never negotiate a version are served V1, so old clients keep working.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    V1,
+    V2,
+}
+
+/// params that were sent as strings in V1 but are numbers since V2.
+const V1_NUMERIC_PARAMS: [&str; 4] = ["row", "column", "version", "last_seq"];
+
+impl ProtocolVersion {
+    pub const DEFAULT: ProtocolVersion = ProtocolVersion::V1;
+    pub const LATEST: ProtocolVersion = ProtocolVersion::V2;
+
+    /// SUBPROTOCOLS lists the `Sec-WebSocket-Protocol` values the server accepts, newest first.
+    pub const SUBPROTOCOLS: [&'static str; 2] = ["tictactoe.v2", "tictactoe.v1"];
+
+    pub fn from_number(version: u32) -> Option<ProtocolVersion> {
+        return match version {
+            1 => Some(ProtocolVersion::V1),
+            2 => Some(ProtocolVersion::V2),
+            _ => None,
+        };
+    }
+
+    pub fn from_subprotocol(subprotocol: &str) -> Option<ProtocolVersion> {
+        return match subprotocol {
+            "tictactoe.v1" => Some(ProtocolVersion::V1),
+            "tictactoe.v2" => Some(ProtocolVersion::V2),
+            _ => None,
+        };
+    }
+
+    pub fn number(&self) -> u32 {
+        return match self {
+            ProtocolVersion::V1 => 1,
+            ProtocolVersion::V2 => 2,
+        };
+    }
+
+    pub fn decode(&self, mut value: Value) -> Result<WebSocketMessage, serde_json::Error> {
+        if *self == ProtocolVersion::V1 {
+            upgrade_v1_params(&mut value);
+        }
+        return serde_json::from_value(value);
+    }
+
+    /// encode shapes the message like this version does.
+    /// It returns `None` when the message does not exist in this version.
+    pub fn encode(&self, message: &ServerMessage) -> Option<Value> {
+        return match self {
+            ProtocolVersion::V1 => downgrade_to_v1(message),
+            ProtocolVersion::V2 => Some(serde_json::to_value(message).unwrap()),
+        };
+    }
+}
+
+/// WireFormat is how messages are encoded in WebSocket frames.
+/// The server replies in the format of the last frame received from the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WireFormat {
+    /// Json is sent in text frames.
+    Json,
+    /// MessagePack is sent in binary frames.
+    MessagePack,
+}
+
+impl WireFormat {
+    pub fn decode(&self, payload: &[u8]) -> Result<Value, String> {
+        return match self {
+            WireFormat::Json => serde_json::from_slice(payload).map_err(|e| e.to_string()),
+            WireFormat::MessagePack => rmp_serde::from_slice(payload).map_err(|e| e.to_string()),
+        };
+    }
+
+    pub fn encode(&self, value: &Value) -> Message {
+        return match self {
+            WireFormat::Json => Message::from(value.to_string()),
+            WireFormat::MessagePack => Message::from(rmp_serde::to_vec_named(value).unwrap()),
+        };
+    }
+}
+
+/// upgrade_v1_params turns V1 string params into the numbers expected by the typed commands.
+fn upgrade_v1_params(value: &mut Value) {
+    let Some(params) = value.get_mut("params").and_then(Value::as_object_mut) else {
+        return;
+    };
+    for name in V1_NUMERIC_PARAMS {
+        let number = params
+            .get(name)
+            .and_then(Value::as_str)
+            .and_then(|text| text.parse::<u64>().ok());
+        if let Some(number) = number {
+            params.insert(name.to_string(), Value::from(number));
+        }
+    }
+}
+
+/// v1_error_text is the `error` text of the V1 server, which clients may still match on.
+/// Errors the V1 server did not have keep the message of the current one.
+fn v1_error_text(error: ServerError, message: &str) -> &str {
+    return match error {
+        ServerError::NotYourTurn => "Invalid character",
+        ServerError::CellOccupied => "invalid move",
+        _ => message,
+    };
+}
+
+/// downgrade_to_v1 reshapes the message like the V1 server did, which had no `request_id` nor `seq`.
+fn downgrade_to_v1(message: &ServerMessage) -> Option<Value> {
+    let value = match &message.event {
+        ServerEvent::Ack { .. } | ServerEvent::Nack { .. } => None,
+        ServerEvent::Error {
+            room_id,
+            user_id,
+            error,
+            message,
+        } => {
+            let mut value = serde_json::json!({ "error": v1_error_text(*error, message) });
+            if let Some(room_id) = room_id {
+                value["room_id"] = Value::from(room_id.clone());
+            }
+            if let Some(user_id) = user_id {
+                value["user_id"] = Value::from(user_id.clone());
+            }
+            Some(value)
+        }
+        ServerEvent::GameFinished {
+            winner_character, ..
+        } => {
+            let mut value = serde_json::to_value(message).unwrap();
+            let character = serde_json::to_value(winner_character).unwrap();
+            value["winner_character"] =
+                Value::from(character.as_str().unwrap_or_default().to_lowercase());
+            Some(value)
+        }
+        _ => Some(serde_json::to_value(message).unwrap()),
+    };
+    return value.map(|mut value| {
+        if let Some(fields) = value.as_object_mut() {
+            fields.remove("request_id");
+            fields.remove("seq");
+        }
+        value
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::game::{CommandType, FinishReason, GameCharacter};
+
+    fn v1(event: ServerEvent) -> Option<Value> {
+        let message = ServerMessage {
+            request_id: Some("42".to_string()),
+            seq: Some(7),
+            event,
+        };
+        return downgrade_to_v1(&message);
+    }
+
+    #[test]
+    fn upgrade_v1_params_turns_numeric_strings_into_numbers() {
+        let mut value = json!({
+            "command": "move",
+            "params": {"room_id": "12", "user_id": "u", "row": "1", "column": "2", "last_seq": "3"},
+        });
+        upgrade_v1_params(&mut value);
+        assert_eq!(
+            value["params"],
+            json!({"room_id": "12", "user_id": "u", "row": 1, "column": 2, "last_seq": 3})
+        );
+    }
+
+    #[test]
+    fn upgrade_v1_params_keeps_what_it_cannot_parse() {
+        let mut value = json!({"command": "move", "params": {"row": "a", "column": 2}});
+        upgrade_v1_params(&mut value);
+        assert_eq!(value["params"], json!({"row": "a", "column": 2}));
+
+        let mut value = json!({"command": "move"});
+        upgrade_v1_params(&mut value);
+        assert_eq!(value, json!({"command": "move"}));
+    }
+
+    #[test]
+    fn v1_decodes_string_coordinates() {
+        let value = json!({
+            "command": "move",
+            "params": {"room_id": "r", "row": "1", "column": "0"},
+        });
+        let message = ProtocolVersion::V1.decode(value).unwrap();
+        let CommandType::Move { params } = message.command else {
+            panic!("expected a move");
+        };
+        assert_eq!((params.row, params.column), (1, 0));
+    }
+
+    #[test]
+    fn downgrade_to_v1_drops_acknowledgements() {
+        let ack = ServerEvent::Ack {
+            command: "move".to_string(),
+        };
+        assert_eq!(v1(ack), None);
+        let nack = ServerEvent::Nack {
+            error: ServerError::NotYourTurn,
+            message: ServerError::NotYourTurn.message().to_string(),
+        };
+        assert_eq!(v1(nack), None);
+    }
+
+    #[test]
+    fn downgrade_to_v1_keeps_the_error_texts_of_v1() {
+        let error = |error| ServerEvent::error(Some("r".to_string()), Some("u".to_string()), error);
+        assert_eq!(
+            v1(error(ServerError::NotYourTurn)),
+            Some(json!({"room_id": "r", "user_id": "u", "error": "Invalid character"}))
+        );
+        assert_eq!(
+            v1(error(ServerError::CellOccupied)),
+            Some(json!({"room_id": "r", "user_id": "u", "error": "invalid move"}))
+        );
+        assert_eq!(
+            v1(ServerEvent::error(None, None, ServerError::RoomNotFound)),
+            Some(json!({"error": "Room does not exist!"}))
+        );
+    }
+
+    #[test]
+    fn downgrade_to_v1_strips_request_id_and_seq() {
+        let event = ServerEvent::MoveRegistered {
+            room_id: "r".to_string(),
+            user_id: "u".to_string(),
+            board_after_move: vec![vec![Some(GameCharacter::X), None, None]],
+            clocks: None,
+        };
+        assert_eq!(
+            v1(event),
+            Some(json!({
+                "event": "MOVE_REGISTERED",
+                "room_id": "r",
+                "user_id": "u",
+                "board_after_move": [["X", null, null]],
+            }))
+        );
+    }
+
+    #[test]
+    fn downgrade_to_v1_lowercases_the_winner_character() {
+        let event = ServerEvent::GameFinished {
+            room_id: "r".to_string(),
+            user_id: "u".to_string(),
+            winner_user_id: "u".to_string(),
+            winner_display_name: "Alice".to_string(),
+            winner_character: GameCharacter::O,
+            reason: FinishReason::Resignation,
+            winning_line: None,
+        };
+        let value = v1(event).unwrap();
+        assert_eq!(value["winner_character"], json!("o"));
+        assert_eq!(value.get("seq"), None);
+    }
+}
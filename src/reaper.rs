@@ -0,0 +1,44 @@
+use tokio::time;
+
+use crate::game::{AppState, CloseReason, ServerEvent, ServerMessage};
+
+/// reap_rooms periodically removes the rooms whose TTL is up, see `Room::close_reason`.
+/// Their subscribers receive `ROOM_CLOSED` as the last event of the room.
+pub async fn reap_rooms(state: AppState) {
+    let mut interval = time::interval(state.config.room_reaper_interval);
+    loop {
+        interval.tick().await;
+        reap_rooms_once(&state);
+    }
+}
+
+fn reap_rooms_once(state: &AppState) {
+    let mut rooms = match state.rooms.lock() {
+        Ok(rooms) => rooms,
+        Err(e) => {
+            tracing::error!("Fail to lock room: {e}");
+            return;
+        }
+    };
+
+    let closed_rooms: Vec<(String, CloseReason)> = rooms
+        .iter()
+        .filter_map(|(room_id, room)| {
+            return room
+                .close_reason(&state.config)
+                .map(|reason| (room_id.clone(), reason));
+        })
+        .collect();
+    for (room_id, reason) in closed_rooms {
+        let Some(mut room) = rooms.remove(&room_id) else {
+            continue;
+        };
+        tracing::debug!("Closing room {room_id}: {reason:?}");
+        let event = ServerEvent::RoomClosed {
+            room_id: room_id.clone(),
+            reason,
+        };
+        // dropping the room afterwards ends the streams of its subscribers
+        room.broadcast(ServerMessage::new(event, None));
+    }
+}
@@ -0,0 +1,360 @@
+//! HTTP endpoints mirroring the WebSocket commands.
+//! They run the same handlers, so room events are still delivered to the room's WebSockets.
+
+use axum::{
+    Extension, Json,
+    extract::{Path, State, rejection::JsonRejection},
+    http::{HeaderMap, StatusCode},
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use serde::{Deserialize, Serialize};
+use tokio_stream::{
+    StreamExt,
+    wrappers::{BroadcastStream, errors::BroadcastStreamRecvError},
+};
+
+use crate::game::{
+    AppState, CommandType, CreateParams, MoveParams, RoomUserParams, ServerError, ServerEvent,
+};
+use crate::session::Claims;
+
+/// LAST_EVENT_ID is the header an `EventSource` sends when it reconnects.
+const LAST_EVENT_ID: &str = "last-event-id";
+
+/// UserBody is the body of commands that only need the user: `RoomUserParams` without the room,
+/// which is in the path.
+#[derive(Serialize, Deserialize)]
+pub struct UserBody {
+    pub user_id: Option<String>,
+}
+
+/// MoveBody is the body of `POST /rooms/{room_id}/moves`: `MoveParams` without the room.
+#[derive(Serialize, Deserialize)]
+pub struct MoveBody {
+    pub user_id: Option<String>,
+    pub row: usize,
+    pub column: usize,
+}
+
+pub async fn create_room(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    body: Result<Json<CreateParams>, JsonRejection>,
+) -> Response {
+    let params = match body {
+        Ok(Json(params)) => params,
+        Err(e) => return invalid_body(e),
+    };
+    let command = CommandType::Create { params };
+    return execute(&state, claims, &command, StatusCode::CREATED);
+}
+
+pub async fn get_room(State(state): State<AppState>, Path(room_id): Path<String>) -> Response {
+    let snapshot_result = crate::get_room_and_execute_result(&state, &room_id, |room| {
+        return Ok(room.snapshot(room_id.clone()));
+    });
+    return match snapshot_result {
+        Ok(snapshot) => (StatusCode::OK, Json(snapshot)).into_response(),
+        Err(e) => error_response(Some(room_id), None, e),
+    };
+}
+
+/// room_events streams the room's events as Server-Sent Events, for clients that cannot use WebSockets.
+/// Each event's data is the same JSON as the WebSocket message, and its id is the event's `seq`,
+/// so a reconnecting `EventSource` sends `Last-Event-ID` and receives the events it missed first.
+/// Only the players of the room can stream its events.
+pub async fn room_events(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(room_id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let user_id = claims.sub;
+    let last_event_id = headers
+        .get(LAST_EVENT_ID)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let (missed_messages, receiver) = match last_event_id {
+        Some(last_seq) => match crate::subscribe_after(&state, &room_id, &user_id, last_seq) {
+            Ok((missed_events, receiver)) => (missed_events.into_messages(&None), receiver),
+            Err(e) => return error_response(Some(room_id), Some(user_id), e),
+        },
+        None => {
+            let receiver_result = crate::get_room_and_execute_result(&state, &room_id, |room| {
+                room.get_character(&user_id)
+                    .ok_or(ServerError::UserNotInRoom)?;
+                return Ok(room.subscribe());
+            });
+            match receiver_result {
+                Ok(receiver) => (Vec::new(), receiver),
+                Err(e) => return error_response(Some(room_id), Some(user_id), e),
+            }
+        }
+    };
+
+    // the stream owns the presence, so the player is disconnected when the client goes away
+    let presence = crate::RoomPresence::enter(state, room_id, user_id);
+    // caught_up_seq is the last_seq of the latest snapshot sent after a lag:
+    // the events still buffered up to it are already part of that snapshot
+    let mut caught_up_seq = 0;
+    let stream = tokio_stream::iter(missed_messages.into_iter().map(Ok))
+        .chain(BroadcastStream::new(receiver))
+        .filter_map(move |res| {
+            let message = match res {
+                Ok(message) if message.seq.is_some_and(|seq| seq <= caught_up_seq) => return None,
+                Ok(message) => message,
+                Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                    let (message, _) =
+                        crate::catch_up_after_lag(&presence.state, &presence.room_id, skipped)?;
+                    if let ServerEvent::RoomState(snapshot) = &message.event {
+                        caught_up_seq = snapshot.last_seq;
+                    }
+                    message
+                }
+            };
+            // a snapshot brings the client up to its last_seq, so it resumes from there
+            let id = match &message.event {
+                ServerEvent::RoomState(snapshot) => Some(snapshot.last_seq),
+                _ => message.seq,
+            };
+            let mut event = Event::default();
+            if let Some(id) = id {
+                event = event.id(id.to_string());
+            }
+            return Some(event.json_data(message));
+        });
+    return Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response();
+}
+
+pub async fn join_room(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(room_id): Path<String>,
+    body: Result<Json<UserBody>, JsonRejection>,
+) -> Response {
+    return execute_room_user_command(&state, claims, room_id, body, |params| CommandType::Join {
+        params,
+    });
+}
+
+pub async fn register_move(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(room_id): Path<String>,
+    body: Result<Json<MoveBody>, JsonRejection>,
+) -> Response {
+    let body = match body {
+        Ok(Json(body)) => body,
+        Err(e) => return invalid_body(e),
+    };
+    let command = CommandType::Move {
+        params: MoveParams {
+            room_id,
+            user_id: body.user_id,
+            row: body.row,
+            column: body.column,
+        },
+    };
+    return execute(&state, claims, &command, StatusCode::OK);
+}
+
+pub async fn resign(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(room_id): Path<String>,
+    body: Result<Json<UserBody>, JsonRejection>,
+) -> Response {
+    return execute_room_user_command(&state, claims, room_id, body, |params| {
+        CommandType::Resign { params }
+    });
+}
+
+pub async fn offer_draw(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(room_id): Path<String>,
+    body: Result<Json<UserBody>, JsonRejection>,
+) -> Response {
+    return execute_room_user_command(&state, claims, room_id, body, |params| {
+        CommandType::OfferDraw { params }
+    });
+}
+
+pub async fn accept_draw(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(room_id): Path<String>,
+    body: Result<Json<UserBody>, JsonRejection>,
+) -> Response {
+    return execute_room_user_command(&state, claims, room_id, body, |params| {
+        CommandType::AcceptDraw { params }
+    });
+}
+
+pub async fn decline_draw(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(room_id): Path<String>,
+    body: Result<Json<UserBody>, JsonRejection>,
+) -> Response {
+    return execute_room_user_command(&state, claims, room_id, body, |params| {
+        CommandType::DeclineDraw { params }
+    });
+}
+
+pub async fn request_undo(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(room_id): Path<String>,
+    body: Result<Json<UserBody>, JsonRejection>,
+) -> Response {
+    return execute_room_user_command(&state, claims, room_id, body, |params| {
+        CommandType::RequestUndo { params }
+    });
+}
+
+pub async fn accept_undo(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(room_id): Path<String>,
+    body: Result<Json<UserBody>, JsonRejection>,
+) -> Response {
+    return execute_room_user_command(&state, claims, room_id, body, |params| {
+        CommandType::AcceptUndo { params }
+    });
+}
+
+pub async fn decline_undo(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(room_id): Path<String>,
+    body: Result<Json<UserBody>, JsonRejection>,
+) -> Response {
+    return execute_room_user_command(&state, claims, room_id, body, |params| {
+        CommandType::DeclineUndo { params }
+    });
+}
+
+pub async fn request_rematch(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(room_id): Path<String>,
+    body: Result<Json<UserBody>, JsonRejection>,
+) -> Response {
+    return execute_room_user_command(&state, claims, room_id, body, |params| {
+        CommandType::RequestRematch { params }
+    });
+}
+
+pub async fn accept_rematch(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(room_id): Path<String>,
+    body: Result<Json<UserBody>, JsonRejection>,
+) -> Response {
+    return execute_room_user_command(&state, claims, room_id, body, |params| {
+        CommandType::AcceptRematch { params }
+    });
+}
+
+pub async fn leave_room(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path((room_id, user_id)): Path<(String, String)>,
+) -> Response {
+    let command = CommandType::Leave {
+        params: RoomUserParams {
+            room_id,
+            user_id: Some(user_id),
+        },
+    };
+    return execute(&state, claims, &command, StatusCode::OK);
+}
+
+/// execute_room_user_command executes the command `command` builds from the room of the path
+/// and the user of the body, for the endpoints whose body is a `UserBody`.
+fn execute_room_user_command(
+    state: &AppState,
+    claims: Claims,
+    room_id: String,
+    body: Result<Json<UserBody>, JsonRejection>,
+    command: fn(RoomUserParams) -> CommandType,
+) -> Response {
+    let body = match body {
+        Ok(Json(body)) => body,
+        Err(e) => return invalid_body(e),
+    };
+    let params = RoomUserParams {
+        room_id,
+        user_id: body.user_id,
+    };
+    return execute(state, claims, &command(params), StatusCode::OK);
+}
+
+fn execute(
+    state: &AppState,
+    claims: Claims,
+    command: &CommandType,
+    success_status: StatusCode,
+) -> Response {
+    let mut connection = crate::Connection::detached(claims.player());
+    return match crate::execute_command(state, &mut connection, &None, command) {
+        Ok(event) => (success_status, Json(event)).into_response(),
+        Err(e) => error_response(command.room_id(), command.user_id(), e),
+    };
+}
+
+pub(crate) fn invalid_body(rejection: JsonRejection) -> Response {
+    let event = ServerEvent::Error {
+        room_id: None,
+        user_id: None,
+        error: ServerError::InvalidMessage,
+        message: format!("{}: {}", ServerError::InvalidMessage, rejection.body_text()),
+    };
+    return (StatusCode::BAD_REQUEST, Json(event)).into_response();
+}
+
+pub(crate) fn error_response(
+    room_id: Option<String>,
+    user_id: Option<String>,
+    error: ServerError,
+) -> Response {
+    let event = ServerEvent::error(room_id, user_id, error);
+    return (status_code(error), Json(event)).into_response();
+}
+
+fn status_code(error: ServerError) -> StatusCode {
+    return match error {
+        ServerError::InvalidMessage
+        | ServerError::InvalidSettings
+        | ServerError::UnsupportedVersion
+        | ServerError::OutOfBounds => StatusCode::BAD_REQUEST,
+        ServerError::Unauthenticated | ServerError::InvalidCredentials => StatusCode::UNAUTHORIZED,
+        ServerError::IdentityMismatch => StatusCode::FORBIDDEN,
+        ServerError::RoomNotFound => StatusCode::NOT_FOUND,
+        ServerError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        ServerError::VersionAlreadyNegotiated
+        | ServerError::RoomFull
+        | ServerError::UserNotInRoom
+        | ServerError::GameNotStarted
+        | ServerError::GameAlreadyStarted
+        | ServerError::GameAlreadyFinished
+        | ServerError::NotYourTurn
+        | ServerError::CellOccupied
+        | ServerError::DrawAlreadyOffered
+        | ServerError::NoDrawOffer
+        | ServerError::TakebacksDisabled
+        | ServerError::NoMoveToUndo
+        | ServerError::UndoAlreadyRequested
+        | ServerError::NoUndoRequest
+        | ServerError::GameNotFinished
+        | ServerError::RematchAlreadyRequested
+        | ServerError::NoRematchRequest
+        | ServerError::UsernameTaken => StatusCode::CONFLICT,
+    };
+}
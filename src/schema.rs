@@ -0,0 +1,20 @@
+use axum::Json;
+use serde_json::{Value, json};
+
+use crate::game::{ServerMessage, WebSocketMessage};
+use crate::protocol::ProtocolVersion;
+
+/// protocol_schema describes the latest protocol version with JSON Schema, generated from the message types.
+/// `client_message` is what clients send, `server_message` is what the server sends back.
+pub fn protocol_schema() -> Value {
+    return json!({
+        "title": "Tic-Tac-Toe protocol",
+        "version": ProtocolVersion::LATEST.number(),
+        "client_message": schemars::schema_for!(WebSocketMessage),
+        "server_message": schemars::schema_for!(ServerMessage),
+    });
+}
+
+pub async fn get_schema() -> Json<Value> {
+    return Json(protocol_schema());
+}
@@ -0,0 +1,146 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{
+    Json,
+    extract::{Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+
+use crate::game::{AppState, Player, ServerError, ServerEvent};
+
+/// Claims are the content of a session token.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Claims {
+    /// sub is the id of the user the session belongs to.
+    pub sub: String,
+    /// name is the display name of the user.
+    pub name: String,
+    pub iat: u64,
+    pub exp: u64,
+}
+
+impl Claims {
+    pub fn player(&self) -> Player {
+        return Player {
+            user_id: self.sub.clone(),
+            display_name: self.name.clone(),
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SessionResponse {
+    pub token: String,
+    pub user_id: String,
+    pub display_name: String,
+    /// expires_at is a Unix timestamp in seconds.
+    pub expires_at: u64,
+}
+
+/// SessionKeys issue and verify HMAC-SHA256 signed session tokens.
+pub struct SessionKeys {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+}
+
+impl SessionKeys {
+    pub fn new(secret: &[u8]) -> SessionKeys {
+        return SessionKeys {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+        };
+    }
+
+    pub fn issue(&self, player: Player, ttl_secs: u64) -> SessionResponse {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let claims = Claims {
+            sub: player.user_id.clone(),
+            name: player.display_name.clone(),
+            iat: now,
+            exp: now + ttl_secs,
+        };
+        let token = jsonwebtoken::encode(&Header::default(), &claims, &self.encoding).unwrap();
+        return SessionResponse {
+            token,
+            user_id: player.user_id,
+            display_name: player.display_name,
+            expires_at: claims.exp,
+        };
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, ServerError> {
+        let validation = Validation::new(Algorithm::HS256);
+        return match jsonwebtoken::decode::<Claims>(token, &self.decoding, &validation) {
+            Ok(data) => Ok(data.claims),
+            Err(e) => {
+                tracing::debug!("Rejected session token: {e}");
+                Err(ServerError::Unauthenticated)
+            }
+        };
+    }
+}
+
+/// create_guest_session issues a session for a new guest user.
+/// Guests are not stored, so they cannot log in again once the session expires.
+pub async fn create_guest_session(State(state): State<AppState>) -> Response {
+    let user_id = uuid::Uuid::now_v7().to_string();
+    let display_name = format!("Guest-{}", &user_id[user_id.len() - 6..]);
+    let player = Player {
+        user_id,
+        display_name,
+    };
+    let session = state
+        .session_keys
+        .issue(player, state.config.session_ttl.as_secs());
+    return (StatusCode::CREATED, Json(session)).into_response();
+}
+
+/// require_session rejects requests without a valid session token.
+/// The token is read from the `Authorization: Bearer <token>` header, or from the `token` query
+/// parameter for clients that cannot set headers (e.g. browser WebSockets and EventSource).
+/// The verified Claims are added to the request extensions.
+pub async fn require_session(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let claims_result = request_token(&request)
+        .ok_or(ServerError::Unauthenticated)
+        .and_then(|token| state.session_keys.verify(&token));
+    return match claims_result {
+        Ok(claims) => {
+            request.extensions_mut().insert(claims);
+            next.run(request).await
+        }
+        Err(e) => {
+            let event = ServerEvent::error(None, None, e);
+            (StatusCode::UNAUTHORIZED, Json(event)).into_response()
+        }
+    };
+}
+
+fn request_token(request: &Request) -> Option<String> {
+    let bearer_token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if let Some(token) = bearer_token {
+        return Some(token.to_string());
+    }
+
+    return request.uri().query().and_then(|query| {
+        return query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(name, _)| *name == "token")
+            .map(|(_, value)| value.to_string());
+    });
+}